@@ -0,0 +1,208 @@
+use near_sdk::{env, FunctionError};
+use std::fmt;
+
+/*
+ * Errors surfaced to clients. A panic message is "<code>: <description>", the codes never change
+ * once released so clients can map them, the descriptions are for humans and may be reworded.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContractError {
+    NotAllowed,
+    AlreadyInitialized,
+    EmergencyMode,
+    NotEmergencyMode,
+    AlreadyEmergencyMode,
+    Denylisted,
+    StakeNotAllowed,
+    CurrentDepositDisabled,
+    InstantUnstakeDisabled,
+    NoCurrentDeposit,
+    NoPendingUnstake,
+    BelowMinStake,
+    StakeCapExceeded,
+    NoWithdrawable,
+    NeedToUnstake,
+    WithdrawDelay { days: u32 },
+    NoPrincipal,
+    InsufficientTotalStaked,
+    UnsupportedToken,
+    EmptyCode,
+    NoStagedCode,
+    CodeNotDeployable,
+    RollbackAcrossMigration,
+    InvalidApr,
+    InvalidDelay,
+    InvalidFee,
+    InvalidRate,
+    InvalidLoyaltyTiers,
+    NotTokenOwner,
+    TokenNotFound,
+    FixedDepositNotFound,
+    FixedDepositNotMature,
+    ReceiverNotAllowed,
+    TransferToSelf,
+    TransferToContract,
+    ReceiverCapExceeded,
+    FixedDepositLimit,
+    NotEnoughCurrentDeposit,
+    InvalidAmount,
+    NoReferralRewards,
+    TokenBalanceUnavailable,
+    RescueExceedsSurplus { surplus: u128 },
+    MigrationPending,
+    NotInitialized,
+    NoInput,
+    NotEnoughGas,
+    NoStorageToWithdraw,
+    VotingLockNotFound,
+    FixedDepositWithoutToken,
+    InvalidDuration,
+    UnsupportedStakingType,
+    InvalidMsg,
+}
+
+impl ContractError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ContractError::NotAllowed => "ERR_NOT_ALLOWED",
+            ContractError::AlreadyInitialized => "ERR_ALREADY_INITIALIZED",
+            ContractError::EmergencyMode => "ERR_EMERGENCY_MODE",
+            ContractError::NotEmergencyMode => "ERR_NOT_EMERGENCY_MODE",
+            ContractError::AlreadyEmergencyMode => "ERR_ALREADY_EMERGENCY_MODE",
+            ContractError::Denylisted => "ERR_DENYLISTED",
+            ContractError::StakeNotAllowed => "ERR_STAKE_NOT_ALLOWED",
+            ContractError::CurrentDepositDisabled => "ERR_CURRENT_DEPOSIT_DISABLED",
+            ContractError::InstantUnstakeDisabled => "ERR_INSTANT_UNSTAKE_DISABLED",
+            ContractError::NoCurrentDeposit => "ERR_NO_CURRENT_DEPOSIT",
+            ContractError::NoPendingUnstake => "ERR_NO_PENDING_UNSTAKE",
+            ContractError::BelowMinStake => "ERR_BELOW_MIN_STAKE",
+            ContractError::StakeCapExceeded => "ERR_STAKE_CAP_EXCEEDED",
+            ContractError::NoWithdrawable => "ERR_NO_WITHDRAWABLE",
+            ContractError::NeedToUnstake => "ERR_NEED_TO_UNSTAKE",
+            ContractError::WithdrawDelay { .. } => "ERR_WITHDRAW_DELAY",
+            ContractError::NoPrincipal => "ERR_NO_PRINCIPAL",
+            ContractError::InsufficientTotalStaked => "ERR_INSUFFICIENT_TOTAL_STAKED",
+            ContractError::UnsupportedToken => "ERR_UNSUPPORTED_TOKEN",
+            ContractError::EmptyCode => "ERR_EMPTY_CODE",
+            ContractError::NoStagedCode => "ERR_NO_STAGED_CODE",
+            ContractError::CodeNotDeployable => "ERR_CODE_NOT_DEPLOYABLE",
+            ContractError::RollbackAcrossMigration => "ERR_ROLLBACK_ACROSS_MIGRATION",
+            ContractError::InvalidApr => "ERR_INVALID_APR",
+            ContractError::InvalidDelay => "ERR_INVALID_DELAY",
+            ContractError::InvalidFee => "ERR_INVALID_FEE",
+            ContractError::InvalidRate => "ERR_INVALID_RATE",
+            ContractError::InvalidLoyaltyTiers => "ERR_INVALID_LOYALTY_TIERS",
+            ContractError::NotTokenOwner => "ERR_NOT_TOKEN_OWNER",
+            ContractError::TokenNotFound => "ERR_TOKEN_NOT_FOUND",
+            ContractError::FixedDepositNotFound => "ERR_FIXED_DEPOSIT_NOT_FOUND",
+            ContractError::FixedDepositNotMature => "ERR_FIXED_DEPOSIT_NOT_MATURE",
+            ContractError::ReceiverNotAllowed => "ERR_RECEIVER_NOT_ALLOWED",
+            ContractError::TransferToSelf => "ERR_TRANSFER_TO_SELF",
+            ContractError::TransferToContract => "ERR_TRANSFER_TO_CONTRACT",
+            ContractError::ReceiverCapExceeded => "ERR_RECEIVER_CAP_EXCEEDED",
+            ContractError::FixedDepositLimit => "ERR_FIXED_DEPOSIT_LIMIT",
+            ContractError::NotEnoughCurrentDeposit => "ERR_NOT_ENOUGH_CURRENT_DEPOSIT",
+            ContractError::InvalidAmount => "ERR_INVALID_AMOUNT",
+            ContractError::NoReferralRewards => "ERR_NO_REFERRAL_REWARDS",
+            ContractError::TokenBalanceUnavailable => "ERR_TOKEN_BALANCE_UNAVAILABLE",
+            ContractError::RescueExceedsSurplus { .. } => "ERR_RESCUE_EXCEEDS_SURPLUS",
+            ContractError::MigrationPending => "ERR_MIGRATION_PENDING",
+            ContractError::NotInitialized => "ERR_CONTRACT_IS_NOT_INITIALIZED",
+            ContractError::NoInput => "ERR_NO_INPUT",
+            ContractError::NotEnoughGas => "ERR_NOT_ENOUGH_GAS",
+            ContractError::NoStorageToWithdraw => "ERR_NO_STORAGE_TO_WITHDRAW",
+            ContractError::VotingLockNotFound => "ERR_VOTING_LOCK_NOT_FOUND",
+            ContractError::FixedDepositWithoutToken => "ERR_FIXED_DEPOSIT_WITHOUT_TOKEN",
+            ContractError::InvalidDuration => "ERR_INVALID_DURATION",
+            ContractError::UnsupportedStakingType => "ERR_UNSUPPORTED_STAKING_TYPE",
+            ContractError::InvalidMsg => "ERR_INVALID_MSG",
+        }
+    }
+
+    pub fn description(&self) -> String {
+        match self {
+            ContractError::NotAllowed => "only the owner can call this".to_string(),
+            ContractError::AlreadyInitialized => "Already initialized".to_string(),
+            ContractError::EmergencyMode => "contract is in emergency mode".to_string(),
+            ContractError::NotEmergencyMode => "only available in emergency mode".to_string(),
+            ContractError::AlreadyEmergencyMode => "contract is already in emergency mode".to_string(),
+            ContractError::Denylisted => "account is denylisted".to_string(),
+            ContractError::StakeNotAllowed => "account is not allowed to stake".to_string(),
+            ContractError::CurrentDepositDisabled => "current deposit is disabled".to_string(),
+            ContractError::InstantUnstakeDisabled => "instant unstake is disabled".to_string(),
+            ContractError::NoCurrentDeposit => "No current deposit to unstake".to_string(),
+            ContractError::NoPendingUnstake => "No pending unstake to cancel".to_string(),
+            ContractError::BelowMinStake => "stake amount is below the minimum".to_string(),
+            ContractError::StakeCapExceeded => "stake would exceed the staking cap".to_string(),
+            ContractError::NoWithdrawable => "The withdrawable amount is zero".to_string(),
+            ContractError::NeedToUnstake => "need to unstake".to_string(),
+            ContractError::WithdrawDelay { days } => format!("need to wait for {} days", days),
+            ContractError::NoPrincipal => "No principal to withdraw".to_string(),
+            ContractError::InsufficientTotalStaked => "amount is greater than total_current_staked_amount".to_string(),
+            ContractError::UnsupportedToken => "unsupported token".to_string(),
+            ContractError::EmptyCode => "code is empty".to_string(),
+            ContractError::NoStagedCode => "no staged code".to_string(),
+            ContractError::CodeNotDeployable => "staged code is not deployable yet".to_string(),
+            ContractError::RollbackAcrossMigration => "the previous code can't read the migrated state".to_string(),
+            ContractError::InvalidApr => "apr must be positive".to_string(),
+            ContractError::InvalidDelay => "delay_in_days must be positive".to_string(),
+            ContractError::InvalidFee => "fee must be less than 10000".to_string(),
+            ContractError::InvalidRate => "rate must not exceed 10000".to_string(),
+            ContractError::InvalidLoyaltyTiers => "tiers must be in ascending order".to_string(),
+            ContractError::NotTokenOwner => "not the token owner".to_string(),
+            ContractError::TokenNotFound => "Token not found".to_string(),
+            ContractError::FixedDepositNotFound => "fixed deposit not found".to_string(),
+            ContractError::FixedDepositNotMature => "fixed deposit is not mature".to_string(),
+            ContractError::ReceiverNotAllowed => "receiver is not allowed to stake".to_string(),
+            ContractError::TransferToSelf => "can not transfer to yourself".to_string(),
+            ContractError::TransferToContract => "can not transfer to the staking contract".to_string(),
+            ContractError::ReceiverCapExceeded => "receiver would exceed the user cap".to_string(),
+            ContractError::FixedDepositLimit => format!("an account can hold at most {} fixed deposits", crate::MAX_FIXED_DEPOSITS_PER_USER),
+            ContractError::NotEnoughCurrentDeposit => "not enough current deposit".to_string(),
+            ContractError::InvalidAmount => "amount must be positive".to_string(),
+            ContractError::NoReferralRewards => "No referral rewards to claim".to_string(),
+            ContractError::TokenBalanceUnavailable => "failed to get the token balance".to_string(),
+            ContractError::RescueExceedsSurplus { surplus } => format!("only {} can be rescued", surplus),
+            ContractError::MigrationPending => "users are still being migrated".to_string(),
+            ContractError::NotInitialized => "contract is not initialized".to_string(),
+            ContractError::NoInput => "no input".to_string(),
+            ContractError::NotEnoughGas => "More gas is required".to_string(),
+            ContractError::NoStorageToWithdraw => "no storage balance to withdraw".to_string(),
+            ContractError::VotingLockNotFound => "voting lock not found".to_string(),
+            ContractError::FixedDepositWithoutToken => "fixed deposit has no token".to_string(),
+            ContractError::InvalidDuration => "invalid fixed deposit duration".to_string(),
+            ContractError::UnsupportedStakingType => "unsupported or disabled staking type".to_string(),
+            ContractError::InvalidMsg => "invalid msg".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ContractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.description())
+    }
+}
+
+impl FunctionError for ContractError {
+    fn panic(&self) -> ! {
+        env::panic_str(&self.to_string())
+    }
+}
+
+/// Like `require!`, with a `ContractError` instead of a message.
+pub fn require_or(condition: bool, error: ContractError) {
+    if !condition {
+        error.panic()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(ContractError::NotAllowed.to_string(), "ERR_NOT_ALLOWED: only the owner can call this");
+        assert_eq!(ContractError::WithdrawDelay { days: 3 }.to_string(), "ERR_WITHDRAW_DELAY: need to wait for 3 days");
+    }
+}
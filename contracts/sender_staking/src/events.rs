@@ -2,7 +2,7 @@ use near_sdk::{
     AccountId, log,
     serde::Serialize,
     serde_json::json,
-    json_types::U128,
+    json_types::{Base58CryptoHash, U128},
 };
 
 const EVENT_STANDARD: &str = "sender_staking";
@@ -31,6 +31,112 @@ pub enum Event<'a> {
         amount: &'a U128,
         time: u64
     },
+    Restake{
+        user_id: &'a AccountId,
+        amount: &'a U128,
+        time: u64
+    },
+    StakeRefunded{
+        user_id: &'a AccountId,
+        amount: &'a U128,
+        // a ContractError code, reason is its description
+        code: &'a str,
+        reason: &'a String,
+        time: u64
+    },
+    SolvencyCheck{
+        balance: &'a U128,
+        liabilities: &'a U128,
+        unsettled_interest_bound: &'a U128,
+        surplus: &'a U128,
+        deficit: &'a U128,
+        time: u64
+    },
+    TokensRescued{
+        token_id: &'a AccountId,
+        receiver_id: &'a AccountId,
+        amount: &'a U128,
+        time: u64
+    },
+    RewardsFunded{
+        sender_id: &'a AccountId,
+        amount: &'a U128,
+        time: u64
+    },
+    Renew{
+        user_id: &'a AccountId,
+        token_id: &'a String,
+        amount: &'a U128,
+        interest: &'a U128,
+        maturity: u64,
+        time: u64
+    },
+    FixedDepositRedeem{
+        user_id: &'a AccountId,
+        token_id: &'a String,
+        amount: &'a U128,
+        interest: &'a U128,
+        time: u64
+    },
+    PositionTransfer{
+        sender_id: &'a AccountId,
+        receiver_id: &'a AccountId,
+        position_type: &'a String,
+        amount: &'a U128,
+        time: u64
+    },
+    VotingPowerChange{
+        user_id: &'a AccountId,
+        voting_power: &'a U128,
+        time: u64
+    },
+    ReferrerSet{
+        user_id: &'a AccountId,
+        referrer_id: &'a AccountId
+    },
+    ReferralReward{
+        referrer_id: &'a AccountId,
+        user_id: &'a AccountId,
+        amount: &'a U128
+    },
+    ReferralRewardClaim{
+        referrer_id: &'a AccountId,
+        amount: &'a U128
+    },
+    AllowlistAdd{
+        account_ids: &'a [AccountId]
+    },
+    AllowlistRemove{
+        account_ids: &'a [AccountId]
+    },
+    DenylistAdd{
+        account_ids: &'a [AccountId]
+    },
+    DenylistRemove{
+        account_ids: &'a [AccountId]
+    },
+    InstantUnstake{
+        user_id: &'a AccountId,
+        amount: &'a U128,
+        fee: &'a U128,
+        time: u64
+    },
+    CodeStaged{
+        code_hash: &'a Base58CryptoHash,
+        deployable_time: u64
+    },
+    CodeDeployed{
+        code_hash: &'a Base58CryptoHash,
+        time: u64
+    },
+    EmergencyMode{
+        time: u64
+    },
+    EmergencyWithdraw{
+        user_id: &'a AccountId,
+        amount: &'a U128,
+        time: u64
+    },
 }
 
 impl Event<'_> {
@@ -0,0 +1,445 @@
+use crate::*;
+
+use std::collections::HashMap;
+use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+use near_contract_standards::non_fungible_token::events::{NftBurn, NftMint};
+use near_contract_standards::non_fungible_token::metadata::{
+    NFTContractMetadata, NonFungibleTokenMetadataProvider, TokenMetadata, NFT_METADATA_SPEC,
+};
+use near_contract_standards::non_fungible_token::{
+    NonFungibleTokenEnumeration, NonFungibleTokenResolver, Token, TokenId,
+};
+
+pub const GAS_FOR_REDEEM_CALLBACK: Gas = Gas::from_tgas(30);
+
+/*
+ * Each fixed deposit is a NEP-171 token. The deposit itself lives in the `fixed_deposits` of the
+ * token owner and moves along with every token transfer, so redemption always goes to the holder.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FixedDepositInfo {
+    #[serde(with = "u128_dec_format")]
+    pub amount: Balance,
+    pub apr: u32,
+    pub start_time: u64,
+    pub maturity: u64,
+    #[serde(with = "u128_dec_format")]
+    pub interest: Balance,
+}
+
+impl Contract {
+    pub fn stake_fixed(&mut self, sender_id: AccountId, amount: Balance, duration_days: u32, auto_renew: bool) {
+        let duration = ONE_DAY_IN_SECS * duration_days as u64;
+        let timestamp = nano_to_sec(env::block_timestamp());
+        let interest = fixed_interest(amount, self.fixed_term_apr, duration);
+        let token_id = self.next_fixed_deposit_id.to_string();
+        self.next_fixed_deposit_id += 1;
+
+        let deposit = FixedDepositTerm {
+            amount,
+            start_time: timestamp,
+            duration,
+            accrued_interest: interest,
+            apr: self.fixed_term_apr,
+            token_id: Some(token_id.clone()),
+            auto_renew,
+        };
+        // the contract pays for the token storage, like for the rest of the user record,
+        // min_fixed_deposit_amount and MAX_FIXED_DEPOSITS_PER_USER keep that from being spammed
+        self.nft.internal_mint_with_refund(token_id.clone(), sender_id.clone(), Some(fixed_deposit_metadata(&token_id, &deposit)), None);
+        NftMint {
+            owner_id: &sender_id,
+            token_ids: &[&token_id],
+            memo: None,
+        }.emit();
+
+        let mut user = self.internal_unwrap_user_or_default(&sender_id);
+        self.internal_renew_fixed_deposits(&sender_id, &mut user, timestamp);
+        user.fixed_deposits.push(deposit);
+        self.internal_set_user(&sender_id, user);
+        self.total_fixed_staked_amount += amount;
+        self.total_fixed_interest += interest;
+        self.total_accrued_interest += interest;
+
+        Event::Stake {
+            user_id: &sender_id,
+            stake_type: &"fixed_deposit".to_string(),
+            amount: &U128(amount),
+            duration: duration_days,
+            time: timestamp
+        }.emit();
+    }
+
+    /// Moves the deposit behind `token_id` to the user record of its new owner.
+    pub fn internal_move_fixed_deposit(&mut self, from: &AccountId, to: &AccountId, token_id: &TokenId) {
+        let mut sender = self.internal_unwrap_user_or_default(from);
+        self.internal_renew_fixed_deposits(from, &mut sender, nano_to_sec(env::block_timestamp()));
+        let index = sender.fixed_deposits.iter()
+            .position(|d| d.token_id.as_ref() == Some(token_id))
+            .unwrap_or_else(|| ContractError::FixedDepositNotFound.panic());
+        let deposit = sender.fixed_deposits.remove(index);
+        self.internal_set_user(from, sender);
+        let mut receiver = self.internal_unwrap_user_or_default(to);
+        require_or(receiver.fixed_deposits.len() < MAX_FIXED_DEPOSITS_PER_USER, ContractError::FixedDepositLimit);
+        receiver.fixed_deposits.push(deposit);
+        self.internal_set_user(to, receiver);
+    }
+
+    /// Pays out the matured fixed deposit at `index`, the caller writes `user` back.
+    pub fn internal_redeem_fixed_deposit(&mut self, user_id: &AccountId, user: &mut User, index: usize, timestamp: u64) -> Promise {
+        // the record is gone while the transfer is in flight, so it can neither be redeemed twice nor moved
+        let deposit = user.fixed_deposits.remove(index);
+        let token_id = deposit.token_id.clone().unwrap_or_else(|| ContractError::FixedDepositWithoutToken.panic());
+        self.total_fixed_staked_amount -= deposit.amount;
+        self.total_fixed_interest -= deposit.accrued_interest;
+        self.internal_payout_sent(deposit.amount + deposit.accrued_interest);
+
+        self.internal_ft_transfer(user_id, deposit.amount + deposit.accrued_interest).then(
+            Self::ext(env::current_account_id())
+            .with_static_gas(GAS_FOR_REDEEM_CALLBACK)
+            .on_redeem_fixed_deposit_complete(user_id.clone(), token_id, deposit, timestamp)
+        )
+    }
+
+    /// Renews the matured auto-renewing deposits of `user`, call it before using its fixed deposits.
+    /// Returns how many deposits were renewed.
+    pub fn internal_renew_fixed_deposits(&mut self, user_id: &AccountId, user: &mut User, timestamp: u64) -> u32 {
+        // nothing accrues in emergency mode
+        if self.emergency_mode {
+            return 0;
+        }
+        let apr = self.fixed_term_apr;
+        let mut renewed = 0;
+        for deposit in user.fixed_deposits.iter_mut() {
+            let (old_amount, old_interest) = (deposit.amount, deposit.accrued_interest);
+            if renew_fixed_deposit(deposit, apr, timestamp) == 0 {
+                continue;
+            }
+            renewed += 1;
+            // the interest became principal
+            self.total_fixed_staked_amount += deposit.amount - old_amount;
+            self.total_fixed_interest = self.total_fixed_interest - old_interest + deposit.accrued_interest;
+            self.total_accrued_interest += deposit.amount + deposit.accrued_interest - old_amount - old_interest;
+            if let Some(token_id) = deposit.token_id.as_ref() {
+                let mut metadata = fixed_deposit_metadata(token_id, deposit);
+                metadata.updated_at = Some(to_iso8601(timestamp));
+                if let Some(metadata_by_id) = &mut self.nft.token_metadata_by_id {
+                    metadata_by_id.insert(token_id, &metadata);
+                }
+                Event::Renew {
+                    user_id,
+                    token_id,
+                    amount: &U128(deposit.amount),
+                    interest: &U128(deposit.accrued_interest),
+                    maturity: deposit.start_time + deposit.duration,
+                    time: timestamp
+                }.emit();
+            }
+        }
+        renewed
+    }
+
+    pub fn internal_burn_fixed_token(&mut self, owner_id: &AccountId, token_id: &TokenId) {
+        self.nft.owner_by_id.remove(token_id);
+        if let Some(metadata_by_id) = &mut self.nft.token_metadata_by_id {
+            metadata_by_id.remove(token_id);
+        }
+        if let Some(tokens_per_owner) = &mut self.nft.tokens_per_owner {
+            if let Some(mut token_ids) = tokens_per_owner.get(owner_id) {
+                token_ids.remove(token_id);
+                if token_ids.is_empty() {
+                    tokens_per_owner.remove(owner_id);
+                } else {
+                    tokens_per_owner.insert(owner_id, &token_ids);
+                }
+            }
+        }
+        NftBurn {
+            owner_id,
+            token_ids: &[token_id],
+            authorized_id: None,
+            memo: None,
+        }.emit();
+    }
+
+    fn internal_assert_fixed_transfer(&self, token_id: &TokenId, receiver_id: &AccountId) -> AccountId {
+        self.assert_not_emergency();
+        let owner_id = self.nft.owner_by_id.get(token_id).unwrap_or_else(|| ContractError::TokenNotFound.panic());
+        self.assert_not_denylisted(&owner_id);
+        require_or(self.internal_can_stake(receiver_id), ContractError::ReceiverNotAllowed);
+        owner_id
+    }
+}
+
+pub fn fixed_interest(amount: Balance, apr: u32, duration: u64) -> Balance {
+    amount * apr as u128 * duration as u128 / (TERM_APR_DEMONINATOR as u128 * ONE_YEAR_IN_SECS as u128)
+}
+
+/// Rolls a matured auto-renewing deposit over, principal plus interest, into new terms of the same
+/// duration at `apr` until it matures after `timestamp`. A renewal starts at the previous maturity.
+/// Returns the number of terms added.
+pub fn renew_fixed_deposit(deposit: &mut FixedDepositTerm, apr: u32, timestamp: u64) -> u32 {
+    let mut terms = 0;
+    while deposit.auto_renew && deposit.duration > 0 && deposit.start_time + deposit.duration <= timestamp {
+        deposit.amount += deposit.accrued_interest;
+        deposit.start_time += deposit.duration;
+        deposit.apr = apr;
+        deposit.accrued_interest = fixed_interest(deposit.amount, apr, deposit.duration);
+        terms += 1;
+    }
+    terms
+}
+
+fn fixed_deposit_metadata(token_id: &TokenId, deposit: &FixedDepositTerm) -> TokenMetadata {
+    let maturity = deposit.start_time + deposit.duration;
+    let info = FixedDepositInfo {
+        amount: deposit.amount,
+        apr: deposit.apr,
+        start_time: deposit.start_time,
+        maturity,
+        interest: deposit.accrued_interest,
+    };
+    TokenMetadata {
+        title: Some(format!("Fixed deposit #{}", token_id)),
+        description: Some(format!(
+            "{} staked for {} days at {}.{:02}% APR",
+            deposit.amount, deposit.duration / ONE_DAY_IN_SECS, deposit.apr / 100, deposit.apr % 100
+        )),
+        media: None,
+        media_hash: None,
+        copies: Some(1),
+        issued_at: Some(to_iso8601(deposit.start_time)),
+        expires_at: Some(to_iso8601(maturity)),
+        starts_at: Some(to_iso8601(deposit.start_time)),
+        updated_at: None,
+        extra: Some(serde_json::to_string(&info).unwrap()),
+        reference: None,
+        reference_hash: None,
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Pays out principal and interest of a matured fixed deposit to the owner of its token.
+    #[payable]
+    pub fn redeem_fixed_deposit(&mut self, token_id: TokenId) -> Promise {
+        assert_one_yocto();
+        self.assert_not_emergency();
+        let predecessor_id = env::predecessor_account_id();
+        self.assert_not_denylisted(&predecessor_id);
+        require_or(self.nft.owner_by_id.get(&token_id) == Some(predecessor_id.clone()), ContractError::NotTokenOwner);
+        let timestamp = nano_to_sec(env::block_timestamp());
+
+        let mut user = self.internal_unwrap_user_or_default(&predecessor_id);
+        self.internal_renew_fixed_deposits(&predecessor_id, &mut user, timestamp);
+        let index = user.fixed_deposits.iter()
+            .position(|d| d.token_id.as_ref() == Some(&token_id))
+            .unwrap_or_else(|| ContractError::FixedDepositNotFound.panic());
+        // an auto-renewing deposit never stays mature, turn auto_renew off first
+        require_or(timestamp >= user.fixed_deposits[index].start_time + user.fixed_deposits[index].duration, ContractError::FixedDepositNotMature);
+        let promise = self.internal_redeem_fixed_deposit(&predecessor_id, &mut user, index, timestamp);
+        self.internal_set_user(&predecessor_id, user);
+        promise
+    }
+
+    /// Turns renewal at maturity on or off for the caller's fixed deposit `token_id`.
+    #[payable]
+    pub fn set_auto_renew(&mut self, token_id: TokenId, auto_renew: bool) {
+        assert_one_yocto();
+        let predecessor_id = env::predecessor_account_id();
+        require_or(self.nft.owner_by_id.get(&token_id) == Some(predecessor_id.clone()), ContractError::NotTokenOwner);
+        let mut user = self.internal_unwrap_user_or_default(&predecessor_id);
+        self.internal_renew_fixed_deposits(&predecessor_id, &mut user, nano_to_sec(env::block_timestamp()));
+        let deposit = user.fixed_deposits.iter_mut()
+            .find(|d| d.token_id.as_ref() == Some(&token_id))
+            .unwrap_or_else(|| ContractError::FixedDepositNotFound.panic());
+        deposit.auto_renew = auto_renew;
+        self.internal_set_user(&predecessor_id, user);
+    }
+
+    /// Renews the matured auto-renewing deposits of `account_id`, anyone can call it.
+    /// Returns how many deposits were renewed.
+    pub fn renew_fixed_deposits(&mut self, account_id: AccountId) -> u32 {
+        let mut user = self.internal_unwrap_user_or_default(&account_id);
+        let renewed = self.internal_renew_fixed_deposits(&account_id, &mut user, nano_to_sec(env::block_timestamp()));
+        if renewed > 0 {
+            self.internal_set_user(&account_id, user);
+        }
+        renewed
+    }
+
+    #[private]
+    pub fn on_redeem_fixed_deposit_complete(&mut self, receiver_id: AccountId, token_id: TokenId, deposit: FixedDepositTerm, timestamp: u64) {
+        if !is_promise_success() {
+            self.internal_payout_resolved(deposit.amount + deposit.accrued_interest, 0);
+            let mut user = self.internal_unwrap_user_or_default(&receiver_id);
+            self.total_fixed_staked_amount += deposit.amount;
+            self.total_fixed_interest += deposit.accrued_interest;
+            user.fixed_deposits.push(deposit);
+            self.internal_set_user(&receiver_id, user);
+            log!("Transfer failed.")
+        } else {
+            self.internal_payout_resolved(deposit.amount + deposit.accrued_interest, deposit.amount + deposit.accrued_interest);
+            self.total_withdrawn_amount += deposit.amount;
+            self.total_withdrawn_interest += deposit.accrued_interest;
+            self.internal_burn_fixed_token(&receiver_id, &token_id);
+            Event::FixedDepositRedeem {
+                user_id: &receiver_id,
+                token_id: &token_id,
+                amount: &U128(deposit.amount),
+                interest: &U128(deposit.accrued_interest),
+                time: timestamp
+            }.emit();
+        }
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenCore for Contract {
+    #[payable]
+    fn nft_transfer(&mut self, receiver_id: AccountId, token_id: TokenId, approval_id: Option<u64>, memo: Option<String>) {
+        let owner_id = self.internal_assert_fixed_transfer(&token_id, &receiver_id);
+        self.nft.nft_transfer(receiver_id.clone(), token_id.clone(), approval_id, memo);
+        self.internal_move_fixed_deposit(&owner_id, &receiver_id, &token_id);
+    }
+
+    #[payable]
+    fn nft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        let owner_id = self.internal_assert_fixed_transfer(&token_id, &receiver_id);
+        let result = self.nft.nft_transfer_call(receiver_id.clone(), token_id.clone(), approval_id, memo, msg);
+        self.internal_move_fixed_deposit(&owner_id, &receiver_id, &token_id);
+        result
+    }
+
+    fn nft_token(&self, token_id: TokenId) -> Option<Token> {
+        self.nft.nft_token(token_id)
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenResolver for Contract {
+    #[private]
+    fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<HashMap<AccountId, u64>>,
+    ) -> bool {
+        let kept = self.nft.nft_resolve_transfer(previous_owner_id.clone(), receiver_id.clone(), token_id.clone(), approved_account_ids);
+        if !kept {
+            // the token went back, so does the deposit
+            self.internal_move_fixed_deposit(&receiver_id, &previous_owner_id, &token_id);
+        }
+        kept
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenEnumeration for Contract {
+    fn nft_total_supply(&self) -> U128 {
+        self.nft.nft_total_supply()
+    }
+
+    fn nft_tokens(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<Token> {
+        self.nft.nft_tokens(from_index, limit)
+    }
+
+    fn nft_supply_for_owner(&self, account_id: AccountId) -> U128 {
+        self.nft.nft_supply_for_owner(account_id)
+    }
+
+    fn nft_tokens_for_owner(&self, account_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<Token> {
+        self.nft.nft_tokens_for_owner(account_id, from_index, limit)
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenMetadataProvider for Contract {
+    fn nft_metadata(&self) -> NFTContractMetadata {
+        NFTContractMetadata {
+            spec: NFT_METADATA_SPEC.to_string(),
+            name: "Sender Staking Fixed Deposit".to_string(),
+            symbol: "SFD".to_string(),
+            icon: None,
+            base_uri: None,
+            reference: None,
+            reference_hash: None,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use super::*;
+    use crate::tests::set_context;
+
+    #[test]
+    fn test_fixed_deposit_token() {
+        set_context(accounts(1), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_fixed(accounts(2), 100 * YOCTO24, 365, false);
+        assert_eq!(contract.total_fixed_staked_amount, 100 * YOCTO24);
+        // 60% apr for a year
+        assert_eq!(contract.total_fixed_interest, 60 * YOCTO24);
+
+        let token = contract.nft_token("0".to_string()).unwrap();
+        assert_eq!(token.owner_id, accounts(2));
+        let metadata = token.metadata.unwrap();
+        assert_eq!(metadata.expires_at, Some("1971-01-01T00:00:00Z".to_string()));
+        let info: FixedDepositInfo = serde_json::from_str(&metadata.extra.unwrap()).unwrap();
+        assert_eq!(info.maturity, ONE_YEAR_IN_SECS);
+
+        set_context(accounts(2), 10);
+        contract.nft_transfer(accounts(3), "0".to_string(), None, None);
+        assert!(contract.internal_unwrap_user_or_default(&accounts(2)).fixed_deposits.is_empty());
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(3)).fixed_deposits[0].amount, 100 * YOCTO24);
+        assert_eq!(contract.nft_supply_for_owner(accounts(3)), U128(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FIXED_DEPOSIT_NOT_MATURE")]
+    fn test_redeem_before_maturity() {
+        set_context(accounts(1), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_fixed(accounts(2), 100 * YOCTO24, 30, false);
+        set_context(accounts(2), 29 * ONE_DAY_IN_SECS);
+        let _ = contract.redeem_fixed_deposit("0".to_string());
+    }
+
+    #[test]
+    fn test_auto_renew() {
+        set_context(accounts(1), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.fixed_term_apr = 36500;
+        contract.stake_fixed(accounts(2), 1000, 10, true);
+        assert_eq!(contract.renew_fixed_deposits(accounts(2)), 0);
+
+        // two terms of 10 days at 1% a day
+        set_context(accounts(3), 25 * ONE_DAY_IN_SECS);
+        assert_eq!(contract.renew_fixed_deposits(accounts(2)), 1);
+        let deposit = contract.internal_unwrap_user_or_default(&accounts(2)).fixed_deposits[0].clone();
+        assert_eq!(deposit.amount, 1210);
+        assert_eq!(deposit.accrued_interest, 121);
+        assert_eq!(deposit.start_time, 20 * ONE_DAY_IN_SECS);
+        assert_eq!(contract.total_fixed_staked_amount, 1210);
+        assert_eq!(contract.total_fixed_interest, 121);
+        assert_eq!(contract.total_accrued_interest, 331);
+
+        set_context(accounts(2), 26 * ONE_DAY_IN_SECS);
+        contract.set_auto_renew("0".to_string(), false);
+        set_context(accounts(2), 45 * ONE_DAY_IN_SECS);
+        assert_eq!(contract.renew_fixed_deposits(accounts(2)), 0);
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(2)).fixed_deposits[0].amount, 1210);
+    }
+}
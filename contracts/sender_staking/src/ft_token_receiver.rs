@@ -1,37 +1,211 @@
 use crate::{
-    env, log, near_bindgen, serde_json, AccountId, Contract, ContractExt,  
-    PromiseOrValue, U128,
+    env, log, near_bindgen, nano_to_sec, require_or, serde_json, AccountId, Contract, ContractError, ContractExt,
+    Event, PromiseOrValue, U128, ONE_DAY_IN_SECS, MAX_LOCK_DURATION_IN_SECS,
+    MAX_FIXED_DEPOSITS_PER_USER,
 };
 
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::serde::{Deserialize, Serialize};
 
+/// Version of the `ft_transfer_call` msg format understood by this contract.
+pub const TRANSFER_CALL_VERSION: u32 = 1;
+
+/*
+ * What to do with tokens received through ft_transfer_call, e.g.
+ * {"version": 1, "action": "stake", "staking_type": "fixed_deposit", "duration": 30}
+ * {"version": 1, "action": "fund_rewards"}
+ * Messages without a version are the original format, {"staking_type": "current_deposit"}, and still stake.
+ */
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "near_sdk::serde")]
-#[serde(untagged)]
+#[serde(tag = "action", rename_all = "snake_case")]
 pub enum TransferCallInfo {
-   StakeInfo{ staking_type: String, duration: Option<u32> },
+    Stake { staking_type: String, duration: Option<u32>, referrer: Option<AccountId>, auto_renew: Option<bool> },
+    FundRewards {},
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct VersionedTransferCallInfo {
+    version: u32,
+    #[serde(flatten)]
+    info: TransferCallInfo,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct LegacyStakeInfo {
+    staking_type: String,
+    duration: Option<u32>,
+    referrer: Option<AccountId>,
+    auto_renew: Option<bool>,
+}
+
+/// None for anything this contract does not understand, including later versions.
+pub fn parse_transfer_call_msg(msg: &str) -> Option<TransferCallInfo> {
+    let value: serde_json::Value = serde_json::from_str(msg).ok()?;
+    if value.get("version").is_some() {
+        let versioned: VersionedTransferCallInfo = serde_json::from_value(value).ok()?;
+        (versioned.version == TRANSFER_CALL_VERSION).then_some(versioned.info)
+    } else {
+        let legacy: LegacyStakeInfo = serde_json::from_value(value).ok()?;
+        Some(TransferCallInfo::Stake {
+            staking_type: legacy.staking_type,
+            duration: legacy.duration,
+            referrer: legacy.referrer,
+            auto_renew: legacy.auto_renew,
+        })
+    }
+}
+
+impl Contract {
+    /// Returns the part of `amount` to refund.
+    fn internal_stake_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: u128,
+        staking_type: String,
+        duration: Option<u32>,
+        referrer: Option<AccountId>,
+        auto_renew: Option<bool>,
+    ) -> Result<u128, ContractError> {
+        if self.emergency_mode {
+            Err(ContractError::EmergencyMode)
+        }
+        else if !self.internal_can_stake(&sender_id) {
+            Err(ContractError::StakeNotAllowed)
+        }
+        else if staking_type == "current_deposit" && self.current_switch {
+            // anything above the caps goes back to the sender
+            let stake_amount = amount.min(self.internal_current_stake_capacity(&sender_id));
+            if stake_amount == 0 {
+                Err(ContractError::StakeCapExceeded)
+            } else if stake_amount < self.min_stake_amount {
+                Err(ContractError::BelowMinStake)
+            } else {
+                self.stake_current(sender_id, stake_amount, referrer);
+                Ok(amount - stake_amount)
+            }
+        }
+        else if staking_type == "fixed_deposit" && self.fixed_switch {
+            // duration in days, the lock can not be longer than what counts for voting power
+            let duration = duration.unwrap_or(0);
+            if duration == 0 || ONE_DAY_IN_SECS * duration as u64 > MAX_LOCK_DURATION_IN_SECS {
+                Err(ContractError::InvalidDuration)
+            } else if amount < self.min_stake_amount.max(self.min_fixed_deposit_amount) {
+                Err(ContractError::BelowMinStake)
+            } else if self.internal_unwrap_user_or_default(&sender_id).fixed_deposits.len() >= MAX_FIXED_DEPOSITS_PER_USER {
+                Err(ContractError::FixedDepositLimit)
+            } else {
+                self.stake_fixed(sender_id, amount, duration, auto_renew.unwrap_or(false));
+                Ok(0)
+            }
+        }
+        else {
+            Err(ContractError::UnsupportedStakingType)
+        }
+    }
 }
 
 #[near_bindgen]
 impl FungibleTokenReceiver for Contract {
     fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
-        assert_eq!(env::predecessor_account_id(), self.token_account_id, "unsupported token");
-        let info: TransferCallInfo = serde_json::from_str::<TransferCallInfo>(&msg).expect("invalid msg");
-        let mut refund = 0;
-        match info {
-            TransferCallInfo::StakeInfo{staking_type, duration:_} => {
-                if staking_type == "current_deposit".to_string() && self.current_switch == true {
-                    self.stake_current(sender_id, amount.0);
-                }
-                else{
-                    refund = amount.0;
-                    log!("unsupported staking type");
-                }
+        require_or(env::predecessor_account_id() == self.token_account_id, ContractError::UnsupportedToken);
+        let timestamp = nano_to_sec(env::block_timestamp());
+        let result = match parse_transfer_call_msg(&msg) {
+            Some(TransferCallInfo::Stake { staking_type, duration, referrer, auto_renew }) => {
+                self.internal_stake_on_transfer(sender_id.clone(), amount.0, staking_type, duration, referrer, auto_renew)
             },
+            Some(TransferCallInfo::FundRewards {}) => {
+                self.reward_reserve += amount.0;
+                Event::RewardsFunded { sender_id: &sender_id, amount: &amount, time: timestamp }.emit();
+                Ok(0)
+            },
+            None => Err(ContractError::InvalidMsg),
+        };
 
+        let (refund, error) = match result {
+            Ok(refund) => (refund, ContractError::StakeCapExceeded),
+            Err(error) => (amount.0, error),
+        };
+        if refund > 0 {
+            log!("{}", error);
+            Event::StakeRefunded {
+                user_id: &sender_id,
+                amount: &U128(refund),
+                code: error.code(),
+                reason: &error.description(),
+                time: timestamp
+            }.emit();
         }
-
         PromiseOrValue::Value(refund.into())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, get_logs};
+
+    use super::*;
+    use crate::tests::set_context;
+    use crate::YOCTO24;
+
+    fn refund(result: PromiseOrValue<U128>) -> u128 {
+        match result {
+            PromiseOrValue::Value(refund) => refund.0,
+            PromiseOrValue::Promise(_) => panic!("expected a value"),
+        }
+    }
+
+    #[test]
+    fn test_parse_transfer_call_msg() {
+        assert!(matches!(
+            parse_transfer_call_msg("{\"staking_type\": \"current_deposit\"}"),
+            Some(TransferCallInfo::Stake { .. })
+        ));
+        assert!(matches!(
+            parse_transfer_call_msg("{\"version\": 1, \"action\": \"fund_rewards\"}"),
+            Some(TransferCallInfo::FundRewards {})
+        ));
+        assert!(parse_transfer_call_msg("{\"version\": 2, \"action\": \"stake\", \"staking_type\": \"current_deposit\"}").is_none());
+        assert!(parse_transfer_call_msg("{\"version\": 1, \"action\": \"burn\"}").is_none());
+        assert!(parse_transfer_call_msg("stake").is_none());
+    }
+
+    #[test]
+    fn test_ft_on_transfer() {
+        set_context(accounts(1), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.current_switch = true;
+
+        assert_eq!(refund(contract.ft_on_transfer(accounts(2), U128(100), "not json".to_string())), 100);
+        assert!(get_logs().iter().any(|log| log.contains("stake_refunded") && log.contains("ERR_INVALID_MSG")));
+
+        let msg = "{\"version\": 1, \"action\": \"stake\", \"staking_type\": \"current_deposit\"}";
+        assert_eq!(refund(contract.ft_on_transfer(accounts(2), U128(100), msg.to_string())), 0);
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(2)).current_deposit.amount, 100);
+
+        let msg = "{\"version\": 1, \"action\": \"fund_rewards\"}";
+        assert_eq!(refund(contract.ft_on_transfer(accounts(3), U128(50), msg.to_string())), 0);
+        assert_eq!(contract.reward_reserve, 50);
+    }
+
+    #[test]
+    fn test_fixed_deposit_limits() {
+        set_context(accounts(1), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.fixed_switch = true;
+        let msg = "{\"version\": 1, \"action\": \"stake\", \"staking_type\": \"fixed_deposit\", \"duration\": 30}";
+
+        // dust would only cost the contract token storage
+        assert_eq!(refund(contract.ft_on_transfer(accounts(2), U128(100), msg.to_string())), 100);
+        assert!(get_logs().iter().any(|log| log.contains("stake_refunded") && log.contains("ERR_BELOW_MIN_STAKE")));
+        assert!(contract.internal_unwrap_user_or_default(&accounts(2)).fixed_deposits.is_empty());
+
+        for _ in 0..MAX_FIXED_DEPOSITS_PER_USER {
+            assert_eq!(refund(contract.ft_on_transfer(accounts(2), U128(YOCTO24), msg.to_string())), 0);
+        }
+        assert_eq!(refund(contract.ft_on_transfer(accounts(2), U128(YOCTO24), msg.to_string())), YOCTO24);
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(2)).fixed_deposits.len(), MAX_FIXED_DEPOSITS_PER_USER);
+    }
+}
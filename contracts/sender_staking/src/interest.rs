@@ -0,0 +1,363 @@
+use crate::*;
+
+pub const ONE_YEAR_IN_SECS: u64 = 365 * ONE_DAY_IN_SECS;
+
+/*
+ * How interest on current deposits is calculated
+ * Simple: interest only on the principal, the historical behaviour
+ * Daily: compounded once per day, partial days accrue linearly
+ * Continuous: compounded continuously, growth = e^(apr * t)
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum InterestMode {
+    Simple,
+    Daily,
+    Continuous,
+}
+
+/// Growth of one unit from `from` to `to` at `apr` in `mode`, scaled by `YOCTO18`. Daily periods start
+/// at multiples of ONE_DAY_IN_SECS rather than at `from`, so splitting `from..to` anywhere gives the
+/// same growth.
+pub fn growth_factor(mode: InterestMode, apr: u128, from: u64, to: u64) -> u128 {
+    match mode {
+        InterestMode::Simple => {
+            YOCTO18 + YOCTO18 * apr * (to - from) as u128 / (TERM_APR_DEMONINATOR as u128 * ONE_YEAR_IN_SECS as u128)
+        },
+        InterestMode::Daily => {
+            let daily_rate = YOCTO18 * apr / (TERM_APR_DEMONINATOR as u128 * 365);
+            // the value within a day grows linearly from what it was when the day started
+            let day_progress = |time: u64| YOCTO18 + daily_rate * (time % ONE_DAY_IN_SECS) as u128 / ONE_DAY_IN_SECS as u128;
+            let days = to / ONE_DAY_IN_SECS - from / ONE_DAY_IN_SECS;
+            fixed_div(fixed_mul(fixed_pow(YOCTO18 + daily_rate, days), day_progress(to)), day_progress(from))
+        },
+        InterestMode::Continuous => {
+            let exponent = YOCTO18 * apr * (to - from) as u128 / (TERM_APR_DEMONINATOR as u128 * ONE_YEAR_IN_SECS as u128);
+            fixed_exp(exponent)
+        },
+    }
+}
+
+impl Contract {
+    /// Interest earned by a current deposit since its `last_stake_time`, not yet folded into `accrued_interest`.
+    /// In compounding modes the already accrued interest earns interest too, so the result does not
+    /// depend on how often the user touches the contract.
+    pub fn internal_current_interest(&self, deposit: &CurrentDepositTerm, timestamp: u64) -> Balance {
+        // nothing accrues after an emergency was declared
+        let timestamp = if self.emergency_mode { timestamp.min(self.emergency_start_time) } else { timestamp };
+        let base = deposit.amount + deposit.accrued_interest;
+        let mut interest = 0;
+        for (mode, apr, from, to) in self.internal_interest_periods(deposit, deposit.last_stake_time, timestamp) {
+            interest += match mode {
+                InterestMode::Simple => {
+                    (deposit.amount * (to - from) as u128 * apr / (TERM_APR_DEMONINATOR as u128)) / ONE_YEAR_IN_SECS as u128
+                },
+                InterestMode::Daily | InterestMode::Continuous => {
+                    let value = base + interest;
+                    fixed_mul(value, growth_factor(mode, apr, from, to)) - value
+                },
+            };
+        }
+        interest
+    }
+
+    /// Splits `from..to` where the interest mode changed and where the deposit reaches a new tier, a
+    /// tier's boost only applies from the moment it is reached. Returns the mode, apr and time span
+    /// of each part.
+    fn internal_interest_periods(&self, deposit: &CurrentDepositTerm, from: u64, to: u64) -> Vec<(InterestMode, u128, u64, u64)> {
+        let mut periods = Vec::new();
+        let mut start = from;
+        while start < to {
+            let (mode, mode_end) = self.internal_interest_mode_at(start);
+            let end = self.internal_next_tier_time(deposit, start).map_or(to, |time| time.min(to)).min(mode_end);
+            periods.push((mode, self.internal_deposit_apr(deposit, start) as u128, start, end));
+            start = end;
+        }
+        periods
+    }
+
+    /// The interest mode in effect at `timestamp` and until when it stayed in effect.
+    fn internal_interest_mode_at(&self, timestamp: u64) -> (InterestMode, u64) {
+        self.previous_interest_modes.iter()
+            .find(|&&(_, end_time)| end_time > timestamp)
+            .copied()
+            .unwrap_or((self.interest_mode, u64::MAX))
+    }
+
+    /// Interest earned by the principal in the withdrawal queue since `last_unstake_time`, it stops
+    /// accruing once the withdraw delay is over.
+    pub fn internal_unbonding_interest(&self, user_id: &AccountId, user: &User, timestamp: u64) -> Balance {
+        let last_unstake_time = user.current_deposit.last_unstake_time;
+        if last_unstake_time == 0 {
+            return 0;
+        }
+        let principal = user.withdrawable_amount.saturating_sub(self.internal_get_unstaked_interest(user_id));
+        let mut end_time = timestamp.min(last_unstake_time + ONE_DAY_IN_SECS * self.current_withdraw_delay as u64);
+        if self.emergency_mode {
+            end_time = end_time.min(self.emergency_start_time);
+        }
+        // each part at the apr in effect then
+        let mut interest = 0;
+        let mut start = last_unstake_time;
+        while start < end_time {
+            let (apr, apr_end) = self.internal_unbonding_apr_at(start);
+            let end = apr_end.min(end_time);
+            interest += (principal * (end - start) as u128 * apr as u128 / (TERM_APR_DEMONINATOR as u128)) / ONE_YEAR_IN_SECS as u128;
+            start = end;
+        }
+        interest
+    }
+
+    /// Apr of the withdrawal queue, 0 while unbonding interest is switched off.
+    pub fn internal_unbonding_apr(&self) -> u32 {
+        if self.unbonding_interest_switch { self.unbonding_term_apr } else { 0 }
+    }
+
+    /// The unbonding apr in effect at `timestamp` and until when it stayed in effect.
+    fn internal_unbonding_apr_at(&self, timestamp: u64) -> (u32, u64) {
+        self.previous_unbonding_aprs.iter()
+            .find(|&&(_, end_time)| end_time > timestamp)
+            .copied()
+            .unwrap_or((self.internal_unbonding_apr(), u64::MAX))
+    }
+
+    /// Call after the unbonding switch or apr changed, with the apr from before: the withdrawal
+    /// queue keeps what it earned at the old apr.
+    pub fn internal_record_unbonding_apr(&mut self, old_apr: u32) {
+        if self.internal_unbonding_apr() != old_apr {
+            self.previous_unbonding_aprs.push((old_apr, nano_to_sec(env::block_timestamp())));
+        }
+    }
+
+    /// Adds the unbonding interest to the withdrawable amount, call it before `last_unstake_time` changes.
+    pub fn internal_settle_unbonding_interest(&mut self, user_id: &AccountId, user: &mut User, timestamp: u64) {
+        let interest = self.internal_unbonding_interest(user_id, user, timestamp);
+        if interest > 0 {
+            user.withdrawable_amount += interest;
+            let unstaked_interest = self.internal_get_unstaked_interest(user_id);
+            self.internal_set_unstaked_interest(user_id, unstaked_interest + interest);
+            self.total_current_unstaked_interest += interest;
+            self.total_pending_withdrawal_interest += interest;
+            self.total_accrued_interest += interest;
+        }
+    }
+
+    /// Upper bound at `timestamp` of the current deposit interest not settled yet. Since
+    /// `interest_bound_time` every deposit, its accrued interest and the bound itself are taken to
+    /// earn the highest apr a tier gives.
+    pub fn internal_interest_bound(&self, timestamp: u64) -> Balance {
+        let timestamp = if self.emergency_mode { timestamp.min(self.emergency_start_time) } else { timestamp };
+        if timestamp <= self.interest_bound_time {
+            return self.unsettled_interest_bound;
+        }
+        let base = self.total_current_staked_amount + self.total_current_accrued_interest;
+        // daily compounding never grows faster than continuous compounding over the same time
+        let mode = if self.interest_mode == InterestMode::Simple { InterestMode::Simple } else { InterestMode::Continuous };
+        let growth = growth_factor(mode, self.internal_max_current_apr() as u128, self.interest_bound_time, timestamp);
+        fixed_mul(base + self.unsettled_interest_bound, growth) - base
+    }
+
+    /// Brings the bound up to now, call it before the staked or accrued totals of current deposits change.
+    pub fn internal_roll_interest_bound(&mut self) {
+        let timestamp = nano_to_sec(env::block_timestamp());
+        self.unsettled_interest_bound = self.internal_interest_bound(timestamp);
+        self.interest_bound_time = self.interest_bound_time.max(timestamp);
+    }
+
+    /// Call when current deposit interest is settled, it moves from the bound to the accrued total.
+    pub fn internal_count_settled_interest(&mut self, interest: Balance) {
+        self.internal_roll_interest_bound();
+        self.unsettled_interest_bound = self.unsettled_interest_bound.saturating_sub(interest);
+        self.total_current_accrued_interest += interest;
+    }
+
+    /// A new apr or tier table also applies to the interest not settled yet, so the
+    /// bound grows along when the best yield goes up. `old_yield` is internal_max_current_yield
+    /// from before the change, the bound must be rolled up to now before it.
+    pub fn internal_rescale_interest_bound(&mut self, old_yield: u128) {
+        let new_yield = self.internal_max_current_yield();
+        if new_yield > old_yield && old_yield > 0 {
+            self.unsettled_interest_bound =
+                (U256::from(self.unsettled_interest_bound) * U256::from(new_yield) / U256::from(old_yield)).as_u128();
+        }
+    }
+
+    /// Yearly growth at the highest apr, minus the principal, scaled by `YOCTO18`.
+    pub fn internal_max_current_yield(&self) -> u128 {
+        growth_factor(self.interest_mode, self.internal_max_current_apr() as u128, 0, ONE_YEAR_IN_SECS) - YOCTO18
+    }
+
+    /// Interest earned but not settled yet, an upper bound, along with the referrers' share of it.
+    /// In the withdrawal queue nobody earns for longer than the withdraw delay. It grows at the
+    /// highest tier apr whatever users actually earn, so it is kept out of the settled liabilities.
+    pub fn internal_unsettled_interest(&self) -> Balance {
+        let current = self.internal_interest_bound(nano_to_sec(env::block_timestamp()));
+        let referral = current * self.referral_reward_rate as u128 / TERM_APR_DEMONINATOR as u128;
+        let delay = ONE_DAY_IN_SECS * self.current_withdraw_delay as u64;
+        // at the highest apr a ticket in the queue may have earned
+        let since = nano_to_sec(env::block_timestamp()).saturating_sub(delay);
+        let apr = self.previous_unbonding_aprs.iter()
+            .filter(|&&(_, end_time)| end_time > since)
+            .map(|&(apr, _)| apr)
+            .fold(self.internal_unbonding_apr(), u32::max);
+        let unbonding = (self.total_pending_withdrawal_amount * delay as u128 * apr as u128 / (TERM_APR_DEMONINATOR as u128)) / ONE_YEAR_IN_SECS as u128;
+        current + referral + unbonding
+    }
+
+    /// Effective annual yield of current deposits, divided by 10000.
+    pub fn internal_current_apy(&self) -> u32 {
+        let growth = growth_factor(self.interest_mode, self.current_term_apr as u128, 0, ONE_YEAR_IN_SECS);
+        ((growth - YOCTO18) * TERM_APR_DEMONINATOR as u128 / YOCTO18) as u32
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+    use crate::tests::set_context;
+
+    fn setup(mode: InterestMode) -> Contract {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.interest_mode = mode;
+        contract
+    }
+
+    #[test]
+    fn test_current_apy() {
+        assert_eq!(setup(InterestMode::Simple).internal_current_apy(), 3200);
+        // (1 + 0.32 / 365)^365 - 1
+        assert_eq!(setup(InterestMode::Daily).internal_current_apy(), 3769);
+        // e^0.32 - 1
+        assert_eq!(setup(InterestMode::Continuous).internal_current_apy(), 3771);
+    }
+
+    #[test]
+    fn test_compound_independent_of_activity() {
+        let contract = setup(InterestMode::Continuous);
+        let mut deposit = CurrentDepositTerm {
+            amount: 1000 * YOCTO24,
+            last_stake_time: 0,
+            last_unstake_time: 0,
+            accrued_interest: 0,
+            staking_since: 0,
+        };
+        let once = contract.internal_current_interest(&deposit, 200 * ONE_DAY_IN_SECS);
+
+        deposit.accrued_interest = contract.internal_current_interest(&deposit, 77 * ONE_DAY_IN_SECS);
+        deposit.last_stake_time = 77 * ONE_DAY_IN_SECS;
+        let twice = deposit.accrued_interest + contract.internal_current_interest(&deposit, 200 * ONE_DAY_IN_SECS);
+
+        // only rounding of the fixed point math may differ
+        assert!(once.abs_diff(twice) < YOCTO18, "{} != {}", once, twice);
+    }
+
+    #[test]
+    fn test_daily_independent_of_activity() {
+        let contract = setup(InterestMode::Daily);
+        let mut deposit = CurrentDepositTerm {
+            amount: 1000 * YOCTO24,
+            last_stake_time: ONE_DAY_IN_SECS / 3,
+            last_unstake_time: 0,
+            accrued_interest: 0,
+            staking_since: 0,
+        };
+        let once = contract.internal_current_interest(&deposit, 200 * ONE_DAY_IN_SECS + 5000);
+
+        // settled in the middle of days
+        let mut settled = 0;
+        for timestamp in [ONE_DAY_IN_SECS / 2, 77 * ONE_DAY_IN_SECS + 12345, 150 * ONE_DAY_IN_SECS + 40000] {
+            let interest = contract.internal_current_interest(&deposit, timestamp);
+            deposit.accrued_interest += interest;
+            deposit.last_stake_time = timestamp;
+            settled += interest;
+        }
+        let often = settled + contract.internal_current_interest(&deposit, 200 * ONE_DAY_IN_SECS + 5000);
+
+        assert!(once.abs_diff(often) < YOCTO18, "{} != {}", once, often);
+    }
+
+    #[test]
+    fn test_interest_mode_change() {
+        let mut contract = setup(InterestMode::Simple);
+        contract.stake_current(accounts(2), 1000 * YOCTO24, None);
+
+        // the first half year stays simple interest
+        set_context(accounts(0), ONE_YEAR_IN_SECS / 2);
+        contract.set_interest_mode(InterestMode::Continuous);
+        let deposit = contract.internal_unwrap_user_or_default(&accounts(2)).current_deposit;
+        assert_eq!(contract.internal_current_interest(&deposit, ONE_YEAR_IN_SECS / 2), 160 * YOCTO24);
+        let interest = contract.internal_current_interest(&deposit, ONE_YEAR_IN_SECS);
+        // 1160 * e^0.16 - 1000
+        assert_eq!(interest / YOCTO24, 361);
+    }
+
+    #[test]
+    fn test_unbonding_apr_change() {
+        let mut contract = setup(InterestMode::Simple);
+        contract.set_unbonding_interest_switch(true);
+        contract.set_unbonding_apr(1000);
+        contract.current_withdraw_delay = 30;
+        contract.stake_current(accounts(2), 365 * YOCTO24, None);
+        set_context(accounts(2), ONE_DAY_IN_SECS);
+        contract.unstake_current();
+
+        // 10 days at 10%, then nothing while switched off, then 5 days at 20%
+        set_context(accounts(0), 11 * ONE_DAY_IN_SECS);
+        contract.set_unbonding_interest_switch(false);
+        set_context(accounts(0), 13 * ONE_DAY_IN_SECS);
+        contract.set_unbonding_apr(2000);
+        contract.set_unbonding_interest_switch(true);
+        let user = contract.internal_unwrap_user_or_default(&accounts(2));
+        let timestamp = 18 * ONE_DAY_IN_SECS;
+        assert_eq!(contract.internal_unbonding_interest(&accounts(2), &user, timestamp), 2 * YOCTO24);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_RATE")]
+    fn test_unbonding_apr_bounds() {
+        setup(InterestMode::Simple).set_unbonding_apr(10001);
+    }
+
+    #[test]
+    fn test_interest_bound() {
+        let mut contract = setup(InterestMode::Continuous);
+        contract.loyalty_tiers = vec![LoyaltyTier {
+            name: "gold".to_string(),
+            min_amount: 0,
+            min_duration: 30 * ONE_DAY_IN_SECS,
+            apr_multiplier: 15000,
+        }];
+        contract.stake_current(accounts(2), 1000 * YOCTO24, None);
+        set_context(accounts(3), 10 * ONE_DAY_IN_SECS);
+        contract.stake_current(accounts(3), 500 * YOCTO24, None);
+
+        let unsettled = |contract: &Contract, timestamp: u64| -> Balance {
+            [accounts(2), accounts(3)].iter()
+                .map(|user_id| contract.internal_current_interest(&contract.internal_unwrap_user_or_default(user_id).current_deposit, timestamp))
+                .sum()
+        };
+        let timestamp = 100 * ONE_DAY_IN_SECS;
+        let bound = contract.internal_interest_bound(timestamp);
+        assert!(bound >= unsettled(&contract, timestamp), "{} < {}", bound, unsettled(&contract, timestamp));
+
+        set_context(accounts(2), timestamp);
+        contract.stake_current(accounts(2), YOCTO24, None);
+        let timestamp = 200 * ONE_DAY_IN_SECS;
+        let bound = contract.internal_interest_bound(timestamp);
+        assert!(bound >= unsettled(&contract, timestamp), "{} < {}", bound, unsettled(&contract, timestamp));
+        // only the tier boost of the first 30 days is overestimated
+        assert!(bound < unsettled(&contract, timestamp) * 11 / 10);
+
+        // a higher apr also applies to what is not settled yet
+        set_context(accounts(0), timestamp);
+        contract.set_current_apr(6400);
+        assert!(contract.internal_interest_bound(timestamp) >= unsettled(&contract, timestamp));
+    }
+}
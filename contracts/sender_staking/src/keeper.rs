@@ -0,0 +1,200 @@
+use crate::*;
+
+/// Gas kept available for looking at one user in process_batch.
+const GAS_FOR_BATCH_USER: NearGas = NearGas::from_tgas(5);
+const GAS_FOR_KEEPER_REWARD_CALLBACK: Gas = Gas::from_tgas(10);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchResult {
+    // settled items: withdrawals paid out, fixed deposits redeemed or renewed
+    pub processed: u32,
+    // where the next page starts, None once the end of `users` is reached
+    pub next_index: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DueItems {
+    pub withdrawals: u32,
+    pub redemptions: u32,
+    pub renewals: u32,
+}
+
+impl DueItems {
+    /// Gas attached to the transfers and callbacks of the due payouts.
+    fn payout_gas(&self) -> u64 {
+        (GAS_FOR_TRANSFER.as_gas() + GAS_FOR_WITHDRAW_CALLBACK.as_gas()) * self.withdrawals as u64
+            + (GAS_FOR_TRANSFER.as_gas() + GAS_FOR_REDEEM_CALLBACK.as_gas()) * self.redemptions as u64
+    }
+
+    fn total(&self) -> u32 {
+        self.withdrawals + self.redemptions + self.renewals
+    }
+
+    /// Items the keeper is rewarded for. Renewals are not: they move no tokens, and a short auto
+    /// renewing deposit would otherwise farm the reserve.
+    fn payouts(&self) -> u32 {
+        self.withdrawals + self.redemptions
+    }
+}
+
+impl Contract {
+    pub fn internal_due_items(&self, user: &User, timestamp: u64) -> DueItems {
+        let mut due = DueItems::default();
+        let last_unstake_time = user.current_deposit.last_unstake_time;
+        if user.withdrawable_amount > 0 && last_unstake_time > 0
+            && timestamp > last_unstake_time + ONE_DAY_IN_SECS * self.current_withdraw_delay as u64 {
+            due.withdrawals += 1;
+        }
+        for deposit in user.fixed_deposits.iter().filter(|d| d.start_time + d.duration <= timestamp) {
+            if deposit.auto_renew {
+                due.renewals += 1;
+            } else if deposit.token_id.is_some() {
+                due.redemptions += 1;
+            }
+        }
+        due
+    }
+
+    /// Settles everything due for one user, returns the items settled.
+    fn internal_process_user(&mut self, user_id: &AccountId, mut user: User, timestamp: u64) -> DueItems {
+        let due = self.internal_due_items(&user, timestamp);
+        self.internal_renew_fixed_deposits(user_id, &mut user, timestamp);
+        // from the end, so the indexes left to visit stay valid
+        for index in (0..user.fixed_deposits.len()).rev() {
+            let deposit = &user.fixed_deposits[index];
+            if deposit.start_time + deposit.duration <= timestamp && deposit.token_id.is_some() {
+                self.internal_redeem_fixed_deposit(user_id, &mut user, index, timestamp).detach();
+            }
+        }
+        if due.withdrawals > 0 {
+            // writes the user back
+            self.internal_withdraw(user_id, user, timestamp).detach();
+        } else {
+            self.internal_set_user(user_id, user);
+        }
+        due
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Walks up to `limit` users from `from_index` of `users` and settles what is due: withdrawals after
+    /// the withdraw delay, fixed deposits at maturity. Anyone can call it, and gets `keeper_reward` per
+    /// withdrawal or redemption paid out, from the reward reserve. It stops early when gas runs low.
+    pub fn process_batch(&mut self, from_index: Option<u64>, limit: Option<u32>) -> BatchResult {
+        self.assert_not_emergency();
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(u32::MAX) as u64;
+        let timestamp = nano_to_sec(env::block_timestamp());
+
+        // the keeper reward is paid at the end, keep its gas from the start
+        let reward_gas = if self.keeper_reward > 0 && self.reward_reserve > 0 {
+            GAS_FOR_TRANSFER.as_gas() + GAS_FOR_KEEPER_REWARD_CALLBACK.as_gas()
+        } else {
+            0
+        };
+        let mut index = from_index;
+        let mut processed = 0;
+        let mut payouts = 0;
+        while index < from_index.saturating_add(limit) && index < self.users.len() as u64 {
+            let (user_id, user) = self.users.iter().nth(index as usize).map(|(k, v)| (k.clone(), User::from(v.clone()))).unwrap();
+            let due = self.internal_due_items(&user, timestamp);
+            let gas_needed = GAS_FOR_BATCH_USER.as_gas() + due.payout_gas() + reward_gas;
+            if env::used_gas().as_gas() + gas_needed > env::prepaid_gas().as_gas() {
+                break;
+            }
+            if due.total() > 0 && !self.denylist.contains(&user_id) {
+                let settled = self.internal_process_user(&user_id, user, timestamp);
+                processed += settled.total();
+                payouts += settled.payouts();
+            }
+            index += 1;
+        }
+
+        let reward = (self.keeper_reward * payouts as u128).min(self.reward_reserve);
+        if reward > 0 {
+            let keeper_id = env::predecessor_account_id();
+            self.reward_reserve -= reward;
+            self.internal_payout_sent(reward);
+            self.internal_ft_transfer(&keeper_id, reward).then(
+                Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_KEEPER_REWARD_CALLBACK)
+                .on_keeper_reward_complete(U128(reward))
+            ).detach();
+        }
+        log!("Processed {} items of users {} to {}", processed, from_index, index);
+        BatchResult {
+            processed,
+            next_index: if index < self.users.len() as u64 { Some(index) } else { None },
+        }
+    }
+
+    #[private]
+    pub fn on_keeper_reward_complete(&mut self, reward: U128) {
+        if !is_promise_success() {
+            self.internal_payout_resolved(reward.0, 0);
+            self.reward_reserve += reward.0;
+            log!("Transfer failed.")
+        } else {
+            self.internal_payout_resolved(reward.0, reward.0);
+        }
+    }
+
+    /// Items process_batch would settle among `limit` users from `from_index`.
+    pub fn get_due_items(&self, from_index: Option<u64>, limit: Option<u32>) -> DueItems {
+        let timestamp = nano_to_sec(env::block_timestamp());
+        let mut due = DueItems::default();
+        for (user_id, user) in self.users.iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(u32::MAX) as usize) {
+            if self.denylist.contains(user_id) {
+                continue;
+            }
+            let user_due = self.internal_due_items(&User::from(user.clone()), timestamp);
+            due.withdrawals += user_due.withdrawals;
+            due.redemptions += user_due.redemptions;
+            due.renewals += user_due.renewals;
+        }
+        due
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use super::*;
+    use crate::tests::set_context;
+
+    #[test]
+    fn test_process_batch() {
+        set_context(accounts(1), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.keeper_reward = 10;
+        contract.reward_reserve = 15;
+        contract.stake_fixed(accounts(2), 1000, 10, false);
+        contract.stake_fixed(accounts(3), 1000, 10, true);
+        contract.stake_fixed(accounts(4), 1000, 20, false);
+
+        set_context(accounts(5), 15 * ONE_DAY_IN_SECS);
+        let due = contract.get_due_items(None, None);
+        assert_eq!((due.withdrawals, due.redemptions, due.renewals), (0, 1, 1));
+
+        let result = contract.process_batch(None, Some(2));
+        assert_eq!(result.processed, 2);
+        assert_eq!(result.next_index, Some(2));
+        assert!(contract.internal_unwrap_user_or_default(&accounts(2)).fixed_deposits.is_empty());
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(3)).fixed_deposits[0].start_time, 10 * ONE_DAY_IN_SECS);
+        // the redemption is rewarded, the renewal is not
+        assert_eq!(contract.reward_reserve, 5);
+
+        set_context(accounts(5), 25 * ONE_DAY_IN_SECS);
+        let result = contract.process_batch(None, None);
+        assert_eq!(result.processed, 2);
+        assert_eq!(result.next_index, None);
+        // the reward is limited by the reserve
+        assert_eq!(contract.reward_reserve, 0);
+    }
+}
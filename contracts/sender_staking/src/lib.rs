@@ -1,29 +1,54 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::UnorderedMap;
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::store::IterableMap;
+use near_sdk::json_types::{Base58CryptoHash, U128};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    assert_one_yocto, env, ext_contract, log, near_bindgen, require, serde_json, AccountId, NearToken, Gas,
-    BorshStorageKey, PanicOnDefault, Promise, PromiseOrValue, PromiseResult
+    assert_one_yocto, env, log, near_bindgen, serde_json, AccountId, NearToken, Gas, GasWeight, CryptoHash,
+    BorshStorageKey, PanicOnDefault, Promise, PromiseOrValue, FunctionError
 };
 use near_contract_standards::fungible_token::Balance;
+use near_contract_standards::non_fungible_token::NonFungibleToken;
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
 
 use serde_json::json;
 
 use near_gas::NearGas;
 
+mod errors;
 mod events;
+mod interest;
 mod owner;
 mod user;
 mod utils;
 mod view;
 mod ft_token_receiver;
 mod migrations;
-
+mod referral;
+mod loyalty;
+mod voting;
+mod snapshot;
+mod position;
+mod fixed_deposit;
+mod receipt;
+mod keeper;
+mod solvency;
+
+pub use crate::errors::*;
 pub use crate::events::*;
+pub use crate::interest::*;
 pub use crate::user::*;
 pub use crate::utils::*;
 pub use crate::ft_token_receiver::*;
+pub use crate::migrations::STATE_VERSION;
+pub use crate::referral::*;
+pub use crate::loyalty::*;
+pub use crate::voting::*;
+pub use crate::snapshot::*;
+pub use crate::fixed_deposit::*;
+pub use crate::receipt::*;
+pub use crate::keeper::*;
+pub use crate::solvency::*;
 
 pub const ONE_YOCTO_NEAR: Balance = 1;
 
@@ -31,6 +56,8 @@ pub const TGAS: u64 = 1_000_000_000_000;
 pub const GAS_FOR_DEPOSIT: NearGas = NearGas::from_tgas(15);
 pub const GAS_FOR_TRANSFER: NearGas = NearGas::from_tgas(30);
 pub const GAS_FOR_TRANSFER_ON_CALL: NearGas = NearGas::from_tgas(45);
+pub const GAS_FOR_MIGRATE_CALL: NearGas = NearGas::from_tgas(5);
+pub const GAS_FOR_WITHDRAW_CALLBACK: NearGas = NearGas::from_tgas(20);
 
 pub const YOCTO8: u128 = 100_000_000;
 pub const YOCTO18: u128 = 1_000_000_000_000_000_000;
@@ -42,17 +69,37 @@ pub const TERM_APR_DEMONINATOR: u32 = 10000;
 pub const DEFAULT_FIXED_TERM_APR: u32 = 6000;   // divided by 10000
 pub const DEFAULT_CURRENT_TERM_APR: u32 = 3200; // divided by 10000
 pub const DEFAULT_WITHDRAW_DAYS: u32 = 21; // days
+pub const DEFAULT_INSTANT_UNSTAKE_FEE: u32 = 500; // divided by 10000
+// each fixed deposit mints a token the contract pays the storage for
+pub const DEFAULT_MIN_FIXED_DEPOSIT_AMOUNT: Balance = YOCTO24;
+pub const MAX_FIXED_DEPOSITS_PER_USER: usize = 20;
+pub const UPGRADE_DELAY_IN_SECS: u64 = 2 * ONE_DAY_IN_SECS;
 
+// raw storage key of the staged contract code
+pub const STAGED_CODE_KEY: &[u8] = b"STAGED_CODE";
 
-// external contract interface for callback
-#[ext_contract(ext_self)]
-pub trait MyContract {
-    fn on_transfer_complete(&mut self, receiver_id: AccountId, amount: u128, time: u64, last_unstake_time: u64);
-}
 
 #[derive(BorshStorageKey, BorshSerialize)]
 pub(crate) enum StorageKey {
     User,
+    Allowlist,
+    Denylist,
+    UnstakedInterest,
+    Users,
+    Referral,
+    VotingLocks,
+    UserCheckpoints { account_hash: CryptoHash },
+    TotalStakedCheckpoints,
+    StakedCheckpoints,
+    NftOwners,
+    NftMetadata,
+    NftEnumeration,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StagedCode {
+    pub code_hash: CryptoHash,
+    pub deployable_time: u64,
 }
 
 #[near_bindgen]
@@ -63,7 +110,9 @@ pub struct Contract {
     // token account id
     pub token_account_id: AccountId,
     // users
-    pub users: UnorderedMap<AccountId, User>,
+    pub users: IterableMap<AccountId, VersionedUser>,
+    // users not moved into `users` yet, see migrate_users_batch
+    pub legacy_users: UnorderedMap<AccountId, VersionedUser>,
 
     pub current_switch: bool,
     pub current_term_apr: u32,
@@ -75,10 +124,107 @@ pub struct Contract {
     pub acc_current_staked_amount: Balance,
     pub total_current_staked_amount: Balance,
 
+    // cumulative, everything unstaked, withdrawn or not, less what was restaked by cancel_unstake
+    // or put back when an instant unstake transfer failed
     pub total_current_unstaked_amount: Balance,
     pub total_current_unstaked_interest: Balance,
 
-    
+    // in the withdrawal queue, counted until the withdrawal transfer succeeds
+    pub total_pending_withdrawal_amount: Balance,
+    pub total_pending_withdrawal_interest: Balance,
+    // cumulative, paid out by withdraw, instant unstake, emergency withdraw and fixed deposit redemption
+    pub total_withdrawn_amount: Balance,
+    pub total_withdrawn_interest: Balance,
+    // cumulative interest credited to users, current and fixed deposits and unbonding
+    pub total_accrued_interest: Balance,
+    // interest settled into current deposits and not unstaked yet
+    pub total_current_accrued_interest: Balance,
+    // upper bound of the current deposit interest not settled yet, see internal_interest_bound
+    pub unsettled_interest_bound: Balance,
+    pub interest_bound_time: u64,
+
+    pub interest_mode: InterestMode,
+    // modes used before `interest_mode` and when each one ended, oldest first
+    pub previous_interest_modes: Vec<(InterestMode, u64)>,
+
+    // staking limits, None means unlimited
+    pub current_total_cap: Option<Balance>,
+    pub current_user_cap: Option<Balance>,
+    pub min_stake_amount: Balance,
+    pub min_fixed_deposit_amount: Balance,
+
+    // when enabled only allowlisted accounts can stake, denylisted accounts can neither stake nor withdraw
+    pub allowlist_enabled: bool,
+    pub allowlist: UnorderedSet<AccountId>,
+    pub denylist: UnorderedSet<AccountId>,
+
+    // interest part of each user's withdrawable_amount, the rest is principal
+    pub unstaked_interests: LookupMap<AccountId, Balance>,
+
+    // emergency, once started staking and interest accrual stop for good
+    pub emergency_mode: bool,
+    pub emergency_start_time: u64,
+    pub total_emergency_withdrawn_amount: Balance,
+
+    // instant unstake, the fee goes to the treasury if set, otherwise to the reward reserve
+    pub instant_unstake_switch: bool,
+    pub instant_unstake_fee: u32,
+    pub treasury_id: Option<AccountId>,
+    pub reward_reserve: Balance,
+    pub total_instant_unstake_fee: Balance,
+
+    // optional interest on principal waiting in the withdrawal queue
+    pub unbonding_interest_switch: bool,
+    pub unbonding_term_apr: u32,
+    // aprs used before, 0 while switched off, and when each one ended, oldest first
+    pub previous_unbonding_aprs: Vec<(u32, u64)>,
+
+    // layout version of this struct, see migrations.rs
+    pub state_version: u32,
+
+    // code upgrades, hashes are sha256 of the wasm
+    pub staged_code: Option<StagedCode>,
+    pub current_code_hash: Option<CryptoHash>,
+    pub previous_code_hash: Option<CryptoHash>,
+    // the state version the previous code ran on
+    pub previous_state_version: u32,
+
+    // referral, referrers earn a share of their referees' current deposit interest
+    pub referral_reward_rate: u32,
+    pub referrals: LookupMap<AccountId, ReferralStats>,
+    pub total_pending_referral_rewards: Balance,
+    pub total_paid_referral_rewards: Balance,
+
+    pub loyalty_tiers: Vec<LoyaltyTier>,
+
+    // vote-escrow, fixed deposits still locked grouped by end time
+    pub voting_locks: TreeMap<u64, VotingLock>,
+    pub total_voting_locked_amount: Balance,
+    pub total_voting_bias: Balance,
+
+    // history of staked balances
+    pub user_checkpoints: LookupMap<AccountId, Vector<Checkpoint>>,
+    pub total_staked_checkpoints: Vector<Checkpoint>,
+
+    // fixed deposits, each one is a non-fungible token
+    pub nft: NonFungibleToken,
+    pub next_fixed_deposit_id: u64,
+    pub total_fixed_staked_amount: Balance,
+    pub total_fixed_interest: Balance,
+
+    // the contract is also the receipt token of current deposits, see receipt.rs
+    pub receipt_metadata: FungibleTokenMetadata,
+
+    // paid from the reward reserve for each item settled by process_batch
+    pub keeper_reward: Balance,
+
+    // see check_solvency
+    pub solvency_auto_pause: bool,
+    pub last_solvency_check: Option<SolvencyCheck>,
+    // payouts taken off the other totals while their transfer is in flight, see internal_payout_sent
+    pub total_payouts_in_flight: Balance,
+    // cumulative, every successful transfer of `token_account_id` out of the contract
+    pub total_paid_out: Balance,
 }
 
 
@@ -86,11 +232,12 @@ pub struct Contract {
 impl Contract {
     #[init]
     pub fn new(owner_id: AccountId, token_account_id: AccountId) -> Self {
-        require!(!env::state_exists(), "Already initialized");
+        require_or(!env::state_exists(), ContractError::AlreadyInitialized);
         Contract {
             owner_id,
             token_account_id,
-            users: UnorderedMap::new(StorageKey::User),
+            users: IterableMap::new(StorageKey::Users),
+            legacy_users: UnorderedMap::new(StorageKey::User),
             current_switch: true,
             current_term_apr: DEFAULT_CURRENT_TERM_APR,
             fixed_switch: true,
@@ -103,20 +250,103 @@ impl Contract {
             total_current_unstaked_amount: 0,
             total_current_unstaked_interest: 0,
 
+            total_pending_withdrawal_amount: 0,
+            total_pending_withdrawal_interest: 0,
+            total_withdrawn_amount: 0,
+            total_withdrawn_interest: 0,
+            total_accrued_interest: 0,
+            total_current_accrued_interest: 0,
+            unsettled_interest_bound: 0,
+            interest_bound_time: 0,
+
+            interest_mode: InterestMode::Simple,
+            previous_interest_modes: Vec::new(),
+
+            current_total_cap: None,
+            current_user_cap: None,
+            min_stake_amount: 0,
+            min_fixed_deposit_amount: DEFAULT_MIN_FIXED_DEPOSIT_AMOUNT,
+
+            allowlist_enabled: false,
+            allowlist: UnorderedSet::new(StorageKey::Allowlist),
+            denylist: UnorderedSet::new(StorageKey::Denylist),
+
+            unstaked_interests: LookupMap::new(StorageKey::UnstakedInterest),
+
+            emergency_mode: false,
+            emergency_start_time: 0,
+            total_emergency_withdrawn_amount: 0,
+
+            instant_unstake_switch: false,
+            instant_unstake_fee: DEFAULT_INSTANT_UNSTAKE_FEE,
+            treasury_id: None,
+            reward_reserve: 0,
+            total_instant_unstake_fee: 0,
+
+            unbonding_interest_switch: false,
+            unbonding_term_apr: 0,
+            previous_unbonding_aprs: Vec::new(),
+
+            state_version: STATE_VERSION,
+
+            staged_code: None,
+            current_code_hash: None,
+            previous_code_hash: None,
+            previous_state_version: STATE_VERSION,
+
+            referral_reward_rate: 0,
+            referrals: LookupMap::new(StorageKey::Referral),
+            total_pending_referral_rewards: 0,
+            total_paid_referral_rewards: 0,
+
+            loyalty_tiers: Vec::new(),
+
+            voting_locks: TreeMap::new(StorageKey::VotingLocks),
+            total_voting_locked_amount: 0,
+            total_voting_bias: 0,
+
+            user_checkpoints: LookupMap::new(StorageKey::StakedCheckpoints),
+            total_staked_checkpoints: Vector::new(StorageKey::TotalStakedCheckpoints),
+
+            nft: NonFungibleToken::new(
+                StorageKey::NftOwners,
+                env::current_account_id(),
+                Some(StorageKey::NftMetadata),
+                Some(StorageKey::NftEnumeration),
+                None::<StorageKey>,
+            ),
+            next_fixed_deposit_id: 0,
+            total_fixed_staked_amount: 0,
+            total_fixed_interest: 0,
+
+            receipt_metadata: default_receipt_metadata(),
+
+            keeper_reward: 0,
+
+            solvency_auto_pause: false,
+            last_solvency_check: None,
+            total_payouts_in_flight: 0,
+            total_paid_out: 0,
         }
     }
 
     /* ========== CORE FUNCTION ========== */
     pub fn unstake_current(&mut self) {
         let predecessor_id = env::predecessor_account_id();
+        self.assert_not_emergency();
+        self.assert_not_denylisted(&predecessor_id);
         let mut user: User = self.internal_unwrap_user_or_default(&predecessor_id);
-        require!(user.current_deposit.amount > 0, "No current deposit to unstake" );
+        require_or(user.current_deposit.amount > 0, ContractError::NoCurrentDeposit);
         let timestamp = nano_to_sec(env::block_timestamp());
-        let delta_time = timestamp - user.current_deposit.last_stake_time;
-        let interest = (user.current_deposit.amount*delta_time as u128 *(self.current_term_apr as u128)/(TERM_APR_DEMONINATOR as u128))/(365*ONE_DAY_IN_SECS) as u128;
+        let interest = self.internal_current_interest(&user.current_deposit, timestamp);
+        self.internal_settle_referral_reward(&predecessor_id, &user, interest);
+        // the withdrawal queue restarts, settle what is already in it
+        self.internal_settle_unbonding_interest(&predecessor_id, &mut user, timestamp);
 
         let unstake_amount = user.current_deposit.amount;
         let unstake_interest = user.current_deposit.accrued_interest + interest;
+        self.internal_count_settled_interest(interest);
+        self.total_current_accrued_interest = self.total_current_accrued_interest.saturating_sub(unstake_interest);
         user.withdrawable_amount += user.current_deposit.amount;
         user.withdrawable_amount += unstake_interest;
 
@@ -124,12 +354,18 @@ impl Contract {
         user.current_deposit.accrued_interest = 0;
         user.current_deposit.last_unstake_time = timestamp;
 
-        require!(self.total_current_staked_amount >= unstake_amount,"Unstake amount is greater than total_current_staked_amount" );
+        require_or(self.total_current_staked_amount >= unstake_amount, ContractError::InsufficientTotalStaked);
         self.total_current_staked_amount -= unstake_amount;
 
         self.total_current_unstaked_amount += unstake_amount;
         self.total_current_unstaked_interest += unstake_interest;
+        self.total_pending_withdrawal_amount += unstake_amount;
+        self.total_pending_withdrawal_interest += unstake_interest;
+        self.total_accrued_interest += interest;
+        let unstaked_interest = self.internal_get_unstaked_interest(&predecessor_id);
+        self.internal_set_unstaked_interest(&predecessor_id, unstaked_interest + unstake_interest);
         self.internal_set_user(&predecessor_id,user);
+        self.internal_receipt_burn(&predecessor_id, unstake_amount);
 
         Event::Unstake { 
             user_id: &predecessor_id.clone(), 
@@ -139,76 +375,335 @@ impl Contract {
         }.emit();
     }
 
-    #[payable]
-    pub fn withdraw(&mut self) -> Promise {
+    /// Moves the principal waiting in the withdrawal queue back into the current deposit,
+    /// the unstaked interest stays withdrawable. The staking limits apply as to a new stake,
+    /// and the principal is restaked as a whole or not at all.
+    pub fn cancel_unstake(&mut self) {
         let predecessor_id = env::predecessor_account_id();
+        self.assert_not_emergency();
+        require_or(self.current_switch, ContractError::CurrentDepositDisabled);
+        require_or(self.internal_can_stake(&predecessor_id), ContractError::StakeNotAllowed);
         let mut user: User = self.internal_unwrap_user_or_default(&predecessor_id);
         let timestamp = nano_to_sec(env::block_timestamp());
-        
-        require!(user.withdrawable_amount > 0, "The withdrawable amount is zero" );
-        require!(user.current_deposit.last_unstake_time > 0, "need to unstake" );
-        let msg = format!("need to wait for {} days", self.current_withdraw_delay);
-        require!(timestamp > (user.current_deposit.last_unstake_time + ONE_DAY_IN_SECS * self.current_withdraw_delay as u64), msg);
-        
-        let withdraw_amount = user.withdrawable_amount;
-        let last_unstake_time = user.current_deposit.last_unstake_time;
-        user.withdrawable_amount = 0;
-        user.current_deposit.last_unstake_time = 0; // reset last_unstake_time to 0 after withdraw
+        self.internal_settle_unbonding_interest(&predecessor_id, &mut user, timestamp);
+        let unstaked_interest = self.internal_get_unstaked_interest(&predecessor_id);
+        let restake_amount = user.withdrawable_amount.saturating_sub(unstaked_interest);
+        require_or(restake_amount > 0, ContractError::NoPendingUnstake);
+        require_or(restake_amount >= self.min_stake_amount, ContractError::BelowMinStake);
+        require_or(restake_amount <= self.internal_current_stake_capacity(&predecessor_id), ContractError::StakeCapExceeded);
+        let interest = self.internal_current_interest(&user.current_deposit, timestamp);
+        self.internal_settle_referral_reward(&predecessor_id, &user, interest);
+        self.internal_count_settled_interest(interest);
+
+        user.current_deposit.accrued_interest += interest;
+        if user.current_deposit.amount == 0 {
+            user.current_deposit.staking_since = timestamp;
+        }
+        user.current_deposit.amount += restake_amount;
+        user.current_deposit.last_stake_time = timestamp;
+        user.withdrawable_amount -= restake_amount;
+        if user.withdrawable_amount == 0 {
+            user.current_deposit.last_unstake_time = 0; // nothing left to withdraw
+        }
+
+        self.total_current_staked_amount += restake_amount;
+        self.total_current_unstaked_amount = self.total_current_unstaked_amount.saturating_sub(restake_amount);
+        self.total_pending_withdrawal_amount = self.total_pending_withdrawal_amount.saturating_sub(restake_amount);
+        self.total_accrued_interest += interest;
         self.internal_set_user(&predecessor_id,user);
+        self.internal_receipt_mint(&predecessor_id, restake_amount);
 
-        let transfer_promise = Promise::new(self.token_account_id.clone()).function_call(
-            "ft_transfer".to_string(),
-            json!({
-                "receiver_id": predecessor_id.clone(),
-                "amount": U128(withdraw_amount.clone()),
-            }).to_string().into_bytes(),
-            NearToken::from_yoctonear(ONE_YOCTO_NEAR),
-            Gas::from_gas(GAS_FOR_TRANSFER.as_gas())
-        );
+        Event::Restake {
+            user_id: &predecessor_id,
+            amount: &U128(restake_amount),
+            time: timestamp
+        }.emit();
+    }
 
-        return transfer_promise.then(
-            Self::ext(env::current_account_id())
-            .with_static_gas(Gas::from_tgas(20))
-            .on_transfer_complete(predecessor_id.clone(), U128(withdraw_amount), timestamp, last_unstake_time)
+    /// Also works in emergency mode, a matured unstake is paid with the interest settled before.
+    #[payable]
+    pub fn withdraw(&mut self) -> Promise {
+        let predecessor_id = env::predecessor_account_id();
+        self.assert_not_denylisted(&predecessor_id);
+        let user: User = self.internal_unwrap_user_or_default(&predecessor_id);
+        let timestamp = nano_to_sec(env::block_timestamp());
+        
+        require_or(user.withdrawable_amount > 0, ContractError::NoWithdrawable);
+        require_or(user.current_deposit.last_unstake_time > 0, ContractError::NeedToUnstake);
+        require_or(
+            timestamp > (user.current_deposit.last_unstake_time + ONE_DAY_IN_SECS * self.current_withdraw_delay as u64),
+            ContractError::WithdrawDelay { days: self.current_withdraw_delay }
         );
-
+        self.internal_withdraw(&predecessor_id, user, timestamp)
     }  
 
+    /// `interest` is None for callbacks scheduled by code from before the interest split was tracked,
+    /// the whole amount is principal then.
     #[private]
-    pub fn on_transfer_complete(&mut self, receiver_id: AccountId, amount: U128, timestamp: u64, last_unstake_time: u64) {
+    pub fn on_transfer_complete(&mut self, receiver_id: AccountId, amount: U128, interest: Option<U128>, timestamp: u64, last_unstake_time: u64) {
+        let interest = interest.unwrap_or(U128(0));
         // check the result of promise
-        match env::promise_result(0) {
-            PromiseResult::Failed => {
-                let mut user: User = self.internal_unwrap_user_or_default(&receiver_id);
-                user.withdrawable_amount += amount.0; // restore withdrawable_amount if failed
-                if user.current_deposit.last_unstake_time == 0 {
-                    user.current_deposit.last_unstake_time = last_unstake_time; // restore last_unstake_time if failed
+        if !is_promise_success() {
+            let mut user: User = self.internal_unwrap_user_or_default(&receiver_id);
+            user.withdrawable_amount += amount.0; // restore withdrawable_amount if failed
+            if user.current_deposit.last_unstake_time == 0 {
+                user.current_deposit.last_unstake_time = last_unstake_time; // restore last_unstake_time if failed
+            }
+            let unstaked_interest = self.internal_get_unstaked_interest(&receiver_id);
+            self.internal_set_unstaked_interest(&receiver_id, unstaked_interest + interest.0);
+            self.internal_set_user(&receiver_id, user);
+            log!("Transfer failed.")
+        } else {
+            let principal = amount.0 - interest.0;
+            self.total_pending_withdrawal_amount = self.total_pending_withdrawal_amount.saturating_sub(principal);
+            self.total_pending_withdrawal_interest = self.total_pending_withdrawal_interest.saturating_sub(interest.0);
+            self.total_withdrawn_amount += principal;
+            self.total_withdrawn_interest += interest.0;
+            self.total_paid_out += amount.0;
+            // emit withdraw event
+            Event::Withdraw { 
+                user_id: &receiver_id.clone(), 
+                amount: &amount,
+                time: timestamp
+            }.emit(); 
+        }
+    }
+
+    /// Skips `current_withdraw_delay`: principal plus interest, minus the instant unstake fee, is transferred right away.
+    #[payable]
+    pub fn instant_unstake(&mut self) -> Promise {
+        let predecessor_id = env::predecessor_account_id();
+        require_or(self.instant_unstake_switch, ContractError::InstantUnstakeDisabled);
+        self.assert_not_emergency();
+        self.assert_not_denylisted(&predecessor_id);
+        let mut user: User = self.internal_unwrap_user_or_default(&predecessor_id);
+        require_or(user.current_deposit.amount > 0, ContractError::NoCurrentDeposit);
+        let timestamp = nano_to_sec(env::block_timestamp());
+        let interest = self.internal_current_interest(&user.current_deposit, timestamp);
+        self.internal_settle_referral_reward(&predecessor_id, &user, interest);
+        self.total_accrued_interest += interest;
+
+        let unstake_amount = user.current_deposit.amount;
+        let unstake_interest = user.current_deposit.accrued_interest + interest;
+        self.internal_count_settled_interest(interest);
+        self.total_current_accrued_interest = self.total_current_accrued_interest.saturating_sub(unstake_interest);
+        let fee = (unstake_amount + unstake_interest) * self.instant_unstake_fee as u128 / TERM_APR_DEMONINATOR as u128;
+        let withdraw_amount = unstake_amount + unstake_interest - fee;
+
+        user.current_deposit.amount = 0;
+        user.current_deposit.accrued_interest = 0;
+        // interest is settled up to now, also if the transfer fails and the position is put back
+        user.current_deposit.last_stake_time = timestamp;
+
+        require_or(self.total_current_staked_amount >= unstake_amount, ContractError::InsufficientTotalStaked);
+        self.total_current_staked_amount -= unstake_amount;
+
+        self.total_current_unstaked_amount += unstake_amount;
+        self.total_current_unstaked_interest += unstake_interest;
+        // the fee stays in the contract, but is not in any total until the transfer resolves
+        self.internal_payout_sent(unstake_amount + unstake_interest);
+        self.internal_set_user(&predecessor_id,user);
+        self.internal_receipt_burn(&predecessor_id, unstake_amount);
+
+        self.internal_ft_transfer(&predecessor_id, withdraw_amount).then(
+            Self::ext(env::current_account_id())
+            .with_static_gas(Gas::from_tgas(50))
+            .on_instant_unstake_complete(predecessor_id.clone(), U128(unstake_amount), U128(unstake_interest), U128(fee), timestamp)
+        )
+    }
+
+    #[private]
+    pub fn on_instant_unstake_complete(&mut self, receiver_id: AccountId, amount: U128, interest: U128, fee: U128, timestamp: u64) {
+        if !is_promise_success() {
+            self.internal_payout_resolved(amount.0 + interest.0, 0);
+            self.internal_roll_interest_bound();
+            // put the position back, the fee is not charged
+            let mut user: User = self.internal_unwrap_user_or_default(&receiver_id);
+            user.current_deposit.amount += amount.0;
+            user.current_deposit.accrued_interest += interest.0;
+            // it has been earning since the unstake
+            self.unsettled_interest_bound += self.internal_current_interest(&user.current_deposit, nano_to_sec(env::block_timestamp()));
+            self.total_current_accrued_interest += interest.0;
+            self.total_current_staked_amount += amount.0;
+            self.total_current_unstaked_amount -= amount.0;
+            self.total_current_unstaked_interest -= interest.0;
+            self.internal_set_user(&receiver_id, user);
+            self.internal_receipt_mint(&receiver_id, amount.0);
+            log!("Transfer failed.")
+        } else {
+            self.internal_payout_resolved(amount.0 + interest.0, amount.0 + interest.0 - fee.0);
+            // the fee is part of what left the position
+            self.total_withdrawn_amount += amount.0;
+            self.total_withdrawn_interest += interest.0;
+            self.total_instant_unstake_fee += fee.0;
+            if fee.0 > 0 {
+                match self.treasury_id.clone() {
+                    Some(treasury_id) => {
+                        self.internal_payout_sent(fee.0);
+                        self.internal_ft_transfer(&treasury_id, fee.0).then(
+                            Self::ext(env::current_account_id())
+                            .with_static_gas(Gas::from_tgas(10))
+                            .on_fee_transfer_complete(fee)
+                        ).detach();
+                    },
+                    None => self.reward_reserve += fee.0,
                 }
-                self.internal_set_user(&receiver_id, user);
-                log!("Transfer failed.")
-            },
-            PromiseResult::Successful(_result) => {
-                // emit withdraw event
-                Event::Withdraw { 
-                    user_id: &receiver_id.clone(), 
-                    amount: &amount,
-                    time: timestamp
-                }.emit(); 
             }
+            Event::InstantUnstake {
+                user_id: &receiver_id,
+                amount: &U128(amount.0 + interest.0 - fee.0),
+                fee: &fee,
+                time: timestamp
+            }.emit();
+        }
+    }
+
+    #[private]
+    pub fn on_fee_transfer_complete(&mut self, fee: U128) {
+        if !is_promise_success() {
+            // keep the fee in the contract
+            self.internal_payout_resolved(fee.0, 0);
+            self.reward_reserve += fee.0;
+            log!("Fee transfer to treasury failed.")
+        } else {
+            self.internal_payout_resolved(fee.0, fee.0);
+        }
+    }
+
+    /// Only available in emergency mode: returns the principal of the current deposit, of the
+    /// pending withdrawal and of the fixed deposits right away, all interest is forfeited.
+    #[payable]
+    pub fn emergency_withdraw(&mut self) -> Promise {
+        let predecessor_id = env::predecessor_account_id();
+        require_or(self.emergency_mode, ContractError::NotEmergencyMode);
+        self.assert_not_denylisted(&predecessor_id);
+        let mut user: User = self.internal_unwrap_user_or_default(&predecessor_id);
+        let timestamp = nano_to_sec(env::block_timestamp());
+
+        // records from before the interest split was tracked count as principal only
+        let unstaked_interest = self.internal_get_unstaked_interest(&predecessor_id);
+        let unstaked_principal = user.withdrawable_amount.saturating_sub(unstaked_interest);
+        let fixed_principal: Balance = user.fixed_deposits.iter().map(|d| d.amount).sum();
+        let withdraw_amount = user.current_deposit.amount + unstaked_principal + fixed_principal;
+        require_or(withdraw_amount > 0, ContractError::NoPrincipal);
+
+        require_or(self.total_current_staked_amount >= user.current_deposit.amount, ContractError::InsufficientTotalStaked);
+        self.internal_roll_interest_bound();
+        self.total_current_staked_amount -= user.current_deposit.amount;
+        self.total_current_accrued_interest = self.total_current_accrued_interest.saturating_sub(user.current_deposit.accrued_interest);
+        self.total_emergency_withdrawn_amount += withdraw_amount;
+        // the unstaked interest is forfeited
+        self.total_pending_withdrawal_amount = self.total_pending_withdrawal_amount.saturating_sub(unstaked_principal);
+        self.total_pending_withdrawal_interest = self.total_pending_withdrawal_interest.saturating_sub(unstaked_interest);
+        self.internal_receipt_burn(&predecessor_id, user.current_deposit.amount);
+
+        user.withdrawable_amount = 0;
+        user.current_deposit.amount = 0;
+        user.current_deposit.accrued_interest = 0;
+        user.current_deposit.last_unstake_time = 0;
+        for deposit in std::mem::take(&mut user.fixed_deposits) {
+            self.total_fixed_staked_amount -= deposit.amount;
+            self.total_fixed_interest -= deposit.accrued_interest;
+            if let Some(token_id) = deposit.token_id {
+                self.internal_burn_fixed_token(&predecessor_id, &token_id);
+            }
+        }
+        self.internal_set_unstaked_interest(&predecessor_id, 0);
+        self.internal_set_user(&predecessor_id, user);
+        self.internal_payout_sent(withdraw_amount);
+
+        self.internal_ft_transfer(&predecessor_id, withdraw_amount).then(
+            Self::ext(env::current_account_id())
+            .with_static_gas(Gas::from_tgas(20))
+            .on_emergency_withdraw_complete(predecessor_id.clone(), U128(withdraw_amount), timestamp)
+        )
+    }
+
+    #[private]
+    pub fn on_emergency_withdraw_complete(&mut self, receiver_id: AccountId, amount: U128, timestamp: u64) {
+        if !is_promise_success() {
+            self.internal_payout_resolved(amount.0, 0);
+            // keep the principal as a pending withdrawal so it can be claimed again
+            let mut user: User = self.internal_unwrap_user_or_default(&receiver_id);
+            user.withdrawable_amount += amount.0;
+            user.current_deposit.last_unstake_time = timestamp;
+            self.total_emergency_withdrawn_amount -= amount.0;
+            self.total_pending_withdrawal_amount += amount.0;
+            self.internal_set_user(&receiver_id, user);
+            log!("Transfer failed.")
+        } else {
+            self.internal_payout_resolved(amount.0, amount.0);
+            self.total_withdrawn_amount += amount.0;
+            Event::EmergencyWithdraw {
+                user_id: &receiver_id,
+                amount: &amount,
+                time: timestamp
+            }.emit();
         }
     }
 }
 
 impl Contract{
-    pub fn stake_current(&mut self, sender_id: AccountId, amount: Balance) {
+    pub fn internal_ft_transfer(&self, receiver_id: &AccountId, amount: Balance) -> Promise {
+        Promise::new(self.token_account_id.clone()).function_call(
+            "ft_transfer".to_string(),
+            json!({
+                "receiver_id": receiver_id.clone(),
+                "amount": U128(amount),
+            }).to_string().into_bytes(),
+            NearToken::from_yoctonear(ONE_YOCTO_NEAR),
+            Gas::from_gas(GAS_FOR_TRANSFER.as_gas())
+        )
+    }
+
+    /// Pays out the whole withdrawable amount, the caller checked that the withdraw delay is over.
+    pub fn internal_withdraw(&mut self, user_id: &AccountId, mut user: User, timestamp: u64) -> Promise {
+        self.internal_settle_unbonding_interest(user_id, &mut user, timestamp);
+
+        let withdraw_amount = user.withdrawable_amount;
+        let withdraw_interest = self.internal_get_unstaked_interest(user_id);
+        let last_unstake_time = user.current_deposit.last_unstake_time;
+        user.withdrawable_amount = 0;
+        user.current_deposit.last_unstake_time = 0; // reset last_unstake_time to 0 after withdraw
+        self.internal_set_unstaked_interest(user_id, 0);
+        self.internal_set_user(user_id, user);
+
+        self.internal_ft_transfer(user_id, withdraw_amount).then(
+            Self::ext(env::current_account_id())
+            .with_static_gas(Gas::from_gas(GAS_FOR_WITHDRAW_CALLBACK.as_gas()))
+            .on_transfer_complete(user_id.clone(), U128(withdraw_amount), Some(U128(withdraw_interest)), timestamp, last_unstake_time)
+        )
+    }
+
+    /// How much more `user_id` can put into current deposit before hitting the global or per-user cap.
+    pub fn internal_current_stake_capacity(&self, user_id: &AccountId) -> Balance {
+        let mut capacity = Balance::MAX;
+        if let Some(total_cap) = self.current_total_cap {
+            capacity = capacity.min(total_cap.saturating_sub(self.total_current_staked_amount));
+        }
+        if let Some(user_cap) = self.current_user_cap {
+            let user = self.internal_unwrap_user_or_default(user_id);
+            capacity = capacity.min(user_cap.saturating_sub(user.current_deposit.amount));
+        }
+        capacity
+    }
+
+    pub fn stake_current(&mut self, sender_id: AccountId, amount: Balance, referrer_id: Option<AccountId>) {
         let mut user: User = self.internal_unwrap_user_or_default(&sender_id);
+        self.internal_record_referrer(&sender_id, &mut user, referrer_id);
 
         let timestamp = nano_to_sec(env::block_timestamp());
         //log!("timestamp = {:#?}", timestamp);
-        let delta_time = timestamp - user.current_deposit.last_stake_time;
-        let interest = (user.current_deposit.amount*delta_time as u128 *(self.current_term_apr as u128)/(TERM_APR_DEMONINATOR as u128))/(365*ONE_DAY_IN_SECS) as u128;
+        let interest = self.internal_current_interest(&user.current_deposit, timestamp);
+        self.internal_settle_referral_reward(&sender_id, &user, interest);
         // update accrued interest
         user.current_deposit.accrued_interest += interest;
+        self.internal_count_settled_interest(interest);
+        self.total_accrued_interest += interest;
+        // a new staking period starts
+        if user.current_deposit.amount == 0 {
+            user.current_deposit.staking_since = timestamp;
+        }
         // update stake amount
         user.current_deposit.amount += amount;
         // update last_stake_time
@@ -219,6 +714,7 @@ impl Contract{
         self.acc_current_staked_amount += amount;
 
         self.internal_set_user(&sender_id,user);
+        self.internal_receipt_mint(&sender_id, amount);
         Event::Stake { 
             user_id: &sender_id.clone(), 
             stake_type: &"current_deposit".to_string(),
@@ -241,7 +737,7 @@ mod tests {
 
     const TOTAL_SUPPLY: Balance = 1_000_000_000_000_000;
 
-    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+    pub(crate) fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
         let mut builder = VMContextBuilder::new();
         builder
             .current_account_id(accounts(0))
@@ -250,6 +746,16 @@ mod tests {
         builder
     }
 
+    /// Shared by the tests of the other modules: a call from `predecessor_id` at `timestamp` in seconds,
+    /// with one yocto attached for the methods that require it.
+    pub(crate) fn set_context(predecessor_id: AccountId, timestamp: u64) {
+        testing_env!(get_context(predecessor_id)
+            .block_timestamp(timestamp * 1_000_000_000)
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .prepaid_gas(Gas::from_tgas(300))
+            .build());
+    }
+
     #[test]
     fn test_new() {
         let mut context = get_context(accounts(1));
@@ -264,4 +770,135 @@ mod tests {
         let _contract = Contract::default();
     }
 
+    #[test]
+    fn test_cancel_unstake() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(to_nano(1_000)).build());
+        let mut contract = Contract::new(accounts(0), accounts(2));
+        contract.stake_current(accounts(1), 100 * YOCTO24, None);
+
+        testing_env!(context.block_timestamp(to_nano(1_000 + 30 * ONE_DAY_IN_SECS as u32)).build());
+        contract.unstake_current();
+        let user = contract.internal_unwrap_user_or_default(&accounts(1));
+        let interest = contract.internal_get_unstaked_interest(&accounts(1));
+        assert!(interest > 0);
+        assert_eq!(user.withdrawable_amount, 100 * YOCTO24 + interest);
+
+        contract.cancel_unstake();
+        let user = contract.internal_unwrap_user_or_default(&accounts(1));
+        assert_eq!(user.current_deposit.amount, 100 * YOCTO24);
+        assert_eq!(user.withdrawable_amount, interest);
+        assert!(user.current_deposit.last_unstake_time > 0);
+        assert_eq!(contract.total_current_staked_amount, 100 * YOCTO24);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_STAKE_CAP_EXCEEDED")]
+    fn test_cancel_unstake_over_cap() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(to_nano(1_000)).build());
+        let mut contract = Contract::new(accounts(0), accounts(2));
+        contract.current_switch = true;
+        contract.stake_current(accounts(1), 100, None);
+        contract.unstake_current();
+        contract.current_total_cap = Some(150);
+        contract.stake_current(accounts(3), 100, None);
+        contract.cancel_unstake();
+    }
+
+    #[test]
+    fn test_withdrawn_totals() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(to_nano(1_000)).build());
+        let mut contract = Contract::new(accounts(0), accounts(2));
+        contract.stake_current(accounts(1), 100 * YOCTO24, None);
+
+        testing_env!(context.block_timestamp(to_nano(1_000 + 30 * ONE_DAY_IN_SECS as u32)).build());
+        contract.unstake_current();
+        let interest = contract.internal_get_unstaked_interest(&accounts(1));
+        assert_eq!(contract.total_accrued_interest, interest);
+        assert_eq!(contract.total_pending_withdrawal_amount, 100 * YOCTO24);
+        assert_eq!(contract.total_pending_withdrawal_interest, interest);
+
+        testing_env!(context.block_timestamp(to_nano(1_000 + 60 * ONE_DAY_IN_SECS as u32)).build());
+        let _ = contract.withdraw();
+        // still pending until the transfer succeeds
+        assert_eq!(contract.total_pending_withdrawal_amount, 100 * YOCTO24);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![near_sdk::PromiseResult::Successful(vec![])],
+        );
+        contract.on_transfer_complete(accounts(1), U128(100 * YOCTO24 + interest), Some(U128(interest)), 0, 0);
+        assert_eq!(contract.total_pending_withdrawal_amount, 0);
+        assert_eq!(contract.total_pending_withdrawal_interest, 0);
+        assert_eq!(contract.total_withdrawn_amount, 100 * YOCTO24);
+        assert_eq!(contract.total_withdrawn_interest, interest);
+    }
+
+    #[test]
+    fn test_withdraw_in_emergency_mode() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(to_nano(1_000)).build());
+        let mut contract = Contract::new(accounts(0), accounts(2));
+        contract.stake_current(accounts(1), 100 * YOCTO24, None);
+        testing_env!(context.block_timestamp(to_nano(1_000 + 30 * ONE_DAY_IN_SECS as u32)).build());
+        contract.unstake_current();
+        contract.emergency_mode = true;
+
+        testing_env!(context.block_timestamp(to_nano(1_000 + 60 * ONE_DAY_IN_SECS as u32)).build());
+        let _ = contract.withdraw();
+        let user = contract.internal_unwrap_user_or_default(&accounts(1));
+        assert_eq!(user.withdrawable_amount, 0);
+        assert_eq!(contract.internal_get_unstaked_interest(&accounts(1)), 0);
+    }
+
+    #[test]
+    fn test_instant_unstake_failure() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(to_nano(1_000)).build());
+        let mut contract = Contract::new(accounts(0), accounts(2));
+        contract.instant_unstake_switch = true;
+        contract.stake_current(accounts(1), 100 * YOCTO24, None);
+
+        let timestamp = 1_000 + 30 * ONE_DAY_IN_SECS;
+        testing_env!(context.block_timestamp(to_nano(timestamp as u32)).build());
+        let _ = contract.instant_unstake();
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![near_sdk::PromiseResult::Failed],
+        );
+        contract.on_instant_unstake_complete(accounts(1), U128(100 * YOCTO24), U128(YOCTO24), U128(0), timestamp);
+        let user = contract.internal_unwrap_user_or_default(&accounts(1));
+        assert_eq!(user.current_deposit.amount, 100 * YOCTO24);
+        assert_eq!(user.current_deposit.accrued_interest, YOCTO24);
+        // the restored interest is not counted again
+        assert_eq!(user.current_deposit.last_stake_time, timestamp);
+    }
+
+    #[test]
+    fn test_legacy_transfer_callback() {
+        let context = get_context(accounts(0));
+        testing_env!(
+            context.build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![near_sdk::PromiseResult::Failed],
+        );
+        let mut contract = Contract::new(accounts(0), accounts(2));
+        // scheduled by the previous code, without the interest argument
+        contract.on_transfer_complete(accounts(1), U128(100), None, 0, 50);
+        let user = contract.internal_unwrap_user_or_default(&accounts(1));
+        assert_eq!(user.withdrawable_amount, 100);
+        assert_eq!(user.current_deposit.last_unstake_time, 50);
+        assert_eq!(contract.internal_get_unstaked_interest(&accounts(1)), 0);
+    }
+
 }
@@ -0,0 +1,140 @@
+use crate::*;
+
+/// No boost, the base `current_term_apr` applies.
+pub const BASE_APR_MULTIPLIER: u32 = 10000;
+
+/*
+ * A loyalty tier of the current deposit, reached once both the staked amount and the time staked
+ * without interruption meet its thresholds. Tiers are kept in ascending order.
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LoyaltyTier {
+    pub name: String,
+    #[serde(with = "u128_dec_format")]
+    pub min_amount: Balance,
+    pub min_duration: u64, // in seconds
+    pub apr_multiplier: u32, // divided by 10000, applied on top of current_term_apr
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UserTierInfo {
+    pub tier: Option<LoyaltyTier>,
+    pub apr_multiplier: u32,
+    pub staked_duration: u64,
+    pub next_tier: Option<LoyaltyTier>,
+    // what is still missing to reach next_tier
+    pub next_tier_missing_amount: U128,
+    pub next_tier_remaining_duration: u64,
+}
+
+impl Contract {
+    /// Index of the highest tier reached by a current deposit at `timestamp`.
+    pub fn internal_tier_index(&self, deposit: &CurrentDepositTerm, timestamp: u64) -> Option<usize> {
+        if deposit.amount == 0 {
+            return None;
+        }
+        let staked_duration = timestamp.saturating_sub(deposit.staking_since);
+        self.loyalty_tiers.iter().rposition(|tier| {
+            deposit.amount >= tier.min_amount && staked_duration >= tier.min_duration
+        })
+    }
+
+    /// When a current deposit, its amount unchanged, next reaches a higher tier after `timestamp`.
+    pub fn internal_next_tier_time(&self, deposit: &CurrentDepositTerm, timestamp: u64) -> Option<u64> {
+        if deposit.amount == 0 {
+            return None;
+        }
+        self.loyalty_tiers.iter()
+            .filter(|tier| deposit.amount >= tier.min_amount)
+            .map(|tier| deposit.staking_since + tier.min_duration)
+            .filter(|&time| time > timestamp)
+            .min()
+    }
+
+    /// Current term apr of a deposit, boosted by the tier it has reached.
+    pub fn internal_deposit_apr(&self, deposit: &CurrentDepositTerm, timestamp: u64) -> u32 {
+        let multiplier = self.internal_tier_index(deposit, timestamp)
+            .map_or(BASE_APR_MULTIPLIER, |index| self.loyalty_tiers[index].apr_multiplier);
+        (self.current_term_apr as u64 * multiplier as u64 / BASE_APR_MULTIPLIER as u64) as u32
+    }
+
+    /// The highest apr any current deposit can get, boosted by the best tier.
+    pub fn internal_max_current_apr(&self) -> u32 {
+        let multiplier = self.loyalty_tiers.iter()
+            .map(|tier| tier.apr_multiplier)
+            .fold(BASE_APR_MULTIPLIER, u32::max);
+        (self.current_term_apr as u64 * multiplier as u64 / BASE_APR_MULTIPLIER as u64) as u32
+    }
+
+    pub fn internal_user_tier_info(&self, user: &User, timestamp: u64) -> UserTierInfo {
+        let deposit = &user.current_deposit;
+        let index = self.internal_tier_index(deposit, timestamp);
+        let staked_duration = if deposit.amount > 0 { timestamp.saturating_sub(deposit.staking_since) } else { 0 };
+        let next_tier = self.loyalty_tiers.get(index.map_or(0, |i| i + 1)).cloned();
+        UserTierInfo {
+            tier: index.map(|i| self.loyalty_tiers[i].clone()),
+            apr_multiplier: index.map_or(BASE_APR_MULTIPLIER, |i| self.loyalty_tiers[i].apr_multiplier),
+            staked_duration,
+            next_tier_missing_amount: U128(next_tier.as_ref().map_or(0, |t| t.min_amount.saturating_sub(deposit.amount))),
+            next_tier_remaining_duration: next_tier.as_ref().map_or(0, |t| t.min_duration.saturating_sub(staked_duration)),
+            next_tier,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use super::*;
+    use crate::tests::set_context;
+
+    fn tier(name: &str, min_amount: Balance, min_days: u64, apr_multiplier: u32) -> LoyaltyTier {
+        LoyaltyTier { name: name.to_string(), min_amount, min_duration: min_days * ONE_DAY_IN_SECS, apr_multiplier }
+    }
+
+    #[test]
+    fn test_loyalty_tiers() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.set_loyalty_tiers(vec![
+            tier("bronze", 100 * YOCTO24, 0, 11000),
+            tier("silver", 1000 * YOCTO24, 30, 12500),
+            tier("gold", 10000 * YOCTO24, 90, 15000),
+        ]);
+        let mut user = User::new();
+        user.current_deposit.amount = 5000 * YOCTO24;
+
+        let info = contract.internal_user_tier_info(&user, 10 * ONE_DAY_IN_SECS);
+        assert_eq!(info.tier.unwrap().name, "bronze");
+        assert_eq!(info.next_tier.unwrap().name, "silver");
+        assert_eq!(info.next_tier_remaining_duration, 20 * ONE_DAY_IN_SECS);
+        assert_eq!(contract.internal_deposit_apr(&user.current_deposit, 10 * ONE_DAY_IN_SECS), 3520);
+
+        // long enough for gold, but the amount only qualifies for silver
+        let info = contract.internal_user_tier_info(&user, 100 * ONE_DAY_IN_SECS);
+        assert_eq!(info.tier.unwrap().name, "silver");
+        assert_eq!(info.next_tier_missing_amount, U128(5000 * YOCTO24));
+        assert_eq!(info.next_tier_remaining_duration, 0);
+        assert_eq!(contract.internal_deposit_apr(&user.current_deposit, 100 * ONE_DAY_IN_SECS), 4000);
+        // bronze for the first 30 days, silver from then on
+        assert_eq!(contract.internal_next_tier_time(&user.current_deposit, 0), Some(30 * ONE_DAY_IN_SECS));
+        assert_eq!(
+            contract.internal_current_interest(&user.current_deposit, 100 * ONE_DAY_IN_SECS),
+            5000 * YOCTO24 * 30 * 3520 / 10000 / 365 + 5000 * YOCTO24 * 70 * 4000 / 10000 / 365
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "tiers must be in ascending order")]
+    fn test_unordered_tiers() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.set_loyalty_tiers(vec![
+            tier("silver", 1000 * YOCTO24, 30, 12500),
+            tier("bronze", 100 * YOCTO24, 0, 11000),
+        ]);
+    }
+}
@@ -5,51 +5,251 @@
 use crate::*;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::near_bindgen;
+
+/// Layout version of the current `Contract` struct.
+/// When a released layout changes: freeze it below as `ContractVn`, add a variant and a step to
+/// `VersionedContract`, and bump this number.
+pub const STATE_VERSION: u32 = 3;
+
+const STATE_KEY: &[u8] = b"STATE";
+
+/// Gas kept available for each user moved by migrate_users_batch.
+const GAS_FOR_USER_MIGRATION: NearGas = NearGas::from_tgas(5);
+
 //---------------------------------------------------
-//  PREVIOUS Main Contract State for state migrations
+//  PREVIOUS Main Contract States for state migrations
 //---------------------------------------------------
+
+/// Layout before `total_current_unstaked_interest` was tracked.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV1 {
+    pub owner_id: AccountId,
+    pub token_account_id: AccountId,
+    pub users: UnorderedMap<AccountId, VersionedUser>,
+
+    pub current_switch: bool,
+    pub current_term_apr: u32,
+    pub fixed_switch: bool,
+    pub fixed_term_apr: u32,
+
+    pub current_withdraw_delay: u32,
+    pub acc_current_staked_amount: Balance,
+    pub total_current_staked_amount: Balance,
+
+    pub total_current_unstaked_amount: Balance,
+}
+
+/// Layout of release 2.3.3.
 #[derive(BorshDeserialize, BorshSerialize)]
-struct OldState {
-    // owner
+pub struct ContractV2 {
     pub owner_id: AccountId,
-    // token account id
     pub token_account_id: AccountId,
-    // users
-    pub users: UnorderedMap<AccountId, User>,
+    pub users: UnorderedMap<AccountId, VersionedUser>,
 
     pub current_switch: bool,
     pub current_term_apr: u32,
     pub fixed_switch: bool,
     pub fixed_term_apr: u32,
 
-    // current
     pub current_withdraw_delay: u32,
     pub acc_current_staked_amount: Balance,
     pub total_current_staked_amount: Balance,
 
     pub total_current_unstaked_amount: Balance,
     pub total_current_unstaked_interest: Balance,
+}
 
+impl From<ContractV1> for ContractV2 {
+    fn from(old: ContractV1) -> Self {
+        Self {
+            owner_id: old.owner_id,
+            token_account_id: old.token_account_id,
+            users: old.users,
+
+            current_switch: old.current_switch,
+            current_term_apr: old.current_term_apr,
+            fixed_switch: old.fixed_switch,
+            fixed_term_apr: old.fixed_term_apr,
+
+            current_withdraw_delay: old.current_withdraw_delay,
+            acc_current_staked_amount: old.acc_current_staked_amount,
+            total_current_staked_amount: old.total_current_staked_amount,
+
+            total_current_unstaked_amount: old.total_current_unstaked_amount,
+            // not tracked before
+            total_current_unstaked_interest: 0,
+        }
+    }
+}
+
+impl From<ContractV2> for Contract {
+    fn from(old: ContractV2) -> Self {
+        // fixed deposits were never opened before this layout
+        let mut total_staked_checkpoints = Vector::new(StorageKey::TotalStakedCheckpoints);
+        total_staked_checkpoints.push(&Checkpoint { timestamp: 0, amount: old.total_current_staked_amount });
+        Self {
+            owner_id: old.owner_id,
+            token_account_id: old.token_account_id,
+            // entries are moved over in batches by migrate_users_batch
+            users: IterableMap::new(StorageKey::Users),
+            legacy_users: old.users,
+
+            current_switch: old.current_switch,
+            current_term_apr: old.current_term_apr,
+            fixed_switch: old.fixed_switch,
+            fixed_term_apr: old.fixed_term_apr,
+
+            current_withdraw_delay: old.current_withdraw_delay,
+            acc_current_staked_amount: old.acc_current_staked_amount,
+            total_current_staked_amount: old.total_current_staked_amount,
+            total_current_unstaked_amount: old.total_current_unstaked_amount,
+            total_current_unstaked_interest: old.total_current_unstaked_interest,
+
+            // the old unstaked totals include what was withdrawn already, so pending withdrawals of
+            // legacy records are counted as they move into `users`, rescue_tokens and check_solvency
+            // wait for the migration to finish
+            total_pending_withdrawal_amount: 0,
+            total_pending_withdrawal_interest: 0,
+            total_withdrawn_amount: 0,
+            total_withdrawn_interest: 0,
+            total_accrued_interest: 0,
+            // legacy records add their interest as they move into `users`, see internal_count_legacy_user
+            total_current_accrued_interest: 0,
+            unsettled_interest_bound: 0,
+            interest_bound_time: nano_to_sec(env::block_timestamp()),
+
+            interest_mode: InterestMode::Simple,
+            previous_interest_modes: Vec::new(),
+
+            current_total_cap: None,
+            current_user_cap: None,
+            min_stake_amount: 0,
+            min_fixed_deposit_amount: DEFAULT_MIN_FIXED_DEPOSIT_AMOUNT,
+
+            allowlist_enabled: false,
+            allowlist: UnorderedSet::new(StorageKey::Allowlist),
+            denylist: UnorderedSet::new(StorageKey::Denylist),
+
+            unstaked_interests: LookupMap::new(StorageKey::UnstakedInterest),
+
+            emergency_mode: false,
+            emergency_start_time: 0,
+            total_emergency_withdrawn_amount: 0,
+
+            instant_unstake_switch: false,
+            instant_unstake_fee: DEFAULT_INSTANT_UNSTAKE_FEE,
+            treasury_id: None,
+            reward_reserve: 0,
+            total_instant_unstake_fee: 0,
+
+            unbonding_interest_switch: false,
+            unbonding_term_apr: 0,
+            previous_unbonding_aprs: Vec::new(),
+
+            state_version: STATE_VERSION,
+
+            staged_code: None,
+            current_code_hash: None,
+            previous_code_hash: None,
+            previous_state_version: STATE_VERSION,
+
+            referral_reward_rate: 0,
+            referrals: LookupMap::new(StorageKey::Referral),
+            total_pending_referral_rewards: 0,
+            total_paid_referral_rewards: 0,
+
+            loyalty_tiers: Vec::new(),
+
+            voting_locks: TreeMap::new(StorageKey::VotingLocks),
+            total_voting_locked_amount: 0,
+            total_voting_bias: 0,
+
+            user_checkpoints: LookupMap::new(StorageKey::StakedCheckpoints),
+            total_staked_checkpoints,
+
+            nft: NonFungibleToken::new(
+                StorageKey::NftOwners,
+                env::current_account_id(),
+                Some(StorageKey::NftMetadata),
+                Some(StorageKey::NftEnumeration),
+                None::<StorageKey>,
+            ),
+            next_fixed_deposit_id: 0,
+            total_fixed_staked_amount: 0,
+            total_fixed_interest: 0,
+
+            receipt_metadata: default_receipt_metadata(),
+
+            keeper_reward: 0,
+
+            solvency_auto_pause: false,
+            last_solvency_check: None,
+            total_payouts_in_flight: 0,
+            total_paid_out: 0,
+        }
+    }
+}
+
+/// Any contract state this code knows how to read.
+pub enum VersionedContract {
+    V1(ContractV1),
+    V2(ContractV2),
+    V3(Box<Contract>),
+}
+
+impl VersionedContract {
+    /// Detects the layout of the stored state. Borsh only accepts a slice that is consumed exactly,
+    /// so each layout is tried from the newest to the oldest.
+    pub fn read() -> Self {
+        let data = env::storage_read(STATE_KEY).unwrap_or_else(|| ContractError::NotInitialized.panic());
+        if let Ok(state) = Contract::try_from_slice(&data) {
+            if state.state_version == STATE_VERSION {
+                return Self::V3(Box::new(state));
+            }
+        }
+        if let Ok(state) = ContractV2::try_from_slice(&data) {
+            return Self::V2(state);
+        }
+        if let Ok(state) = ContractV1::try_from_slice(&data) {
+            return Self::V1(state);
+        }
+        env::panic_str("Unknown state layout")
+    }
+
+    pub fn version(&self) -> u32 {
+        match self {
+            Self::V1(_) => 1,
+            Self::V2(_) => 2,
+            Self::V3(_) => 3,
+        }
+    }
+
+    /// Applies the migration steps one at a time until the latest layout is reached.
+    pub fn upgrade(self) -> Contract {
+        let mut state = self;
+        loop {
+            state = match state {
+                Self::V1(old) => Self::V2(old.into()),
+                Self::V2(old) => Self::V3(Box::new(old.into())),
+                Self::V3(latest) => return *latest,
+            };
+        }
+    }
 }
 
 #[near_bindgen]
 impl Contract {
     //-----------------
     //-- migration called after code upgrade
-    ///  For next version upgrades, change this function.
     //-- executed after upgrade to NEW CODE
     //-----------------
-    /// This fn WILL be called by this contract from `pub fn upgrade` (started from DAO)
-    /// Originally a **NOOP implementation. KEEP IT if you haven't changed contract state.**
-    /// If you have changed state, you need to implement migration from old state (keep the old struct with different name to deserialize it first).
+    /// This fn WILL be called by this contract from `pub fn deploy_staged_code` (started from DAO)
+    /// It upgrades the stored state from whichever historical layout it is in to the latest one.
+    /// A state that is already at `STATE_VERSION` is returned untouched, so steps never run twice.
     ///
     #[init(ignore_state)] //do not auto-load state before this function
     #[private]
     pub fn migrate() -> Self {
-        // read state with OLD struct
-        // uncomment when state migration is required on upgrade
-        let old: OldState = env::state_read().expect("Old state doesn't exist");
-
         // can only be called by this same contract (it's called from fn upgrade())
         assert_eq!(
             &env::predecessor_account_id(),
@@ -57,28 +257,159 @@ impl Contract {
             "Can only be called by this contract"
         );
 
-        // uncomment when state migration is required on upgrade
-        // Create the new contract state using the data from the old contract state.
-        // returns this struct that gets stored as contract state
-        return Self {
-            // owner
-            owner_id: old.owner_id,
-            // token account id
-            token_account_id: old.token_account_id,
-            // users
-            users: old.users,
+        let state = VersionedContract::read();
+        let version = state.version();
+        if version == STATE_VERSION {
+            log!("State is already at version {}, nothing to migrate", STATE_VERSION);
+        } else {
+            log!("Migrating state from version {} to {}", version, STATE_VERSION);
+        }
+        state.upgrade()
+    }
 
-            current_switch: old.current_switch,
-            current_term_apr: old.current_term_apr,
-            fixed_switch: old.fixed_switch,
-            fixed_term_apr: old.fixed_term_apr,
-            
-            current_withdraw_delay: old.current_withdraw_delay,
-            acc_current_staked_amount: old.acc_current_staked_amount,
-            total_current_staked_amount: old.total_current_staked_amount,
-            total_current_unstaked_amount: old.total_current_unstaked_amount,
-            total_current_unstaked_interest: 0,
+    /// Moves up to `limit` users from the legacy `UnorderedMap` into `users`, stopping early when
+    /// gas runs low. Anyone can call it until it returns 0, the number of users left to move.
+    pub fn migrate_users_batch(&mut self, limit: Option<u32>) -> u64 {
+        let limit = limit.unwrap_or(u32::MAX);
+        let mut moved = 0;
+        while moved < limit && !self.legacy_users.is_empty() {
+            if env::used_gas().as_gas() + GAS_FOR_USER_MIGRATION.as_gas() > env::prepaid_gas().as_gas() {
+                break;
+            }
+            // take from the end, removing from an UnorderedMap swaps the last entry into the hole
+            let user_id = self.legacy_users.keys_as_vector().get(self.legacy_users.len() - 1).unwrap();
+            let user = self.legacy_users.remove(&user_id).unwrap();
+            self.internal_count_legacy_user(&user_id, user.clone());
+            self.users.insert(user_id, user);
+            moved += 1;
+        }
+        log!("Moved {} users, {} left", moved, self.legacy_users.len());
+        self.legacy_users.len()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn setup() {
+        let mut context = VMContextBuilder::new();
+        context.current_account_id(accounts(0)).predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+    }
+
+    fn state_v1() -> ContractV1 {
+        let mut users = UnorderedMap::new(StorageKey::User);
+        let mut user = User::new();
+        user.current_deposit.amount = 100;
+        users.insert(&accounts(1), &user.into());
+        ContractV1 {
+            owner_id: accounts(0),
+            token_account_id: accounts(2),
+            users,
+            current_switch: true,
+            current_term_apr: 1000,
+            fixed_switch: false,
+            fixed_term_apr: 2000,
+            current_withdraw_delay: 7,
+            acc_current_staked_amount: 300,
+            total_current_staked_amount: 100,
+            total_current_unstaked_amount: 200,
+        }
+    }
+
+    #[test]
+    fn test_migrate_from_v1() {
+        setup();
+        env::state_write(&state_v1());
 
-        };
+        let contract = Contract::migrate();
+        assert_eq!(contract.state_version, STATE_VERSION);
+        assert_eq!(contract.token_account_id, accounts(2));
+        assert_eq!(contract.current_withdraw_delay, 7);
+        assert_eq!(contract.total_current_unstaked_amount, 200);
+        assert_eq!(contract.total_current_unstaked_interest, 0);
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(1)).current_deposit.amount, 100);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_migrate_from_v2() {
+        setup();
+        let mut old: ContractV2 = state_v1().into();
+        old.total_current_unstaked_interest = 50;
+        env::state_write(&old);
+
+        let contract = Contract::migrate();
+        assert_eq!(contract.state_version, STATE_VERSION);
+        assert_eq!(contract.current_term_apr, 1000);
+        // the step must keep data that already exists
+        assert_eq!(contract.total_current_unstaked_interest, 50);
+        assert_eq!(contract.interest_mode, InterestMode::Simple);
+        assert_eq!(contract.internal_total_staked_at(0), 100);
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(1)).current_deposit.amount, 100);
+    }
+
+    #[test]
+    fn test_migrate_twice() {
+        setup();
+        let mut old: ContractV2 = state_v1().into();
+        old.total_current_unstaked_interest = 50;
+        env::state_write(&old);
+        let mut contract = Contract::migrate();
+        contract.total_current_unstaked_interest = 80;
+        env::state_write(&contract);
+
+        let contract = Contract::migrate();
+        assert_eq!(contract.total_current_unstaked_interest, 80);
+    }
+
+    #[test]
+    fn test_migrate_users_batch() {
+        setup();
+        let mut old = state_v1();
+        for i in 2..5 {
+            let mut user = User::new();
+            user.current_deposit.amount = i;
+            user.withdrawable_amount = 10;
+            old.users.insert(&accounts(i as usize), &user.into());
+        }
+        env::state_write(&old);
+        let mut contract = Contract::migrate();
+        assert_eq!(contract.get_total_user_num(), 4);
+
+        assert_eq!(contract.migrate_users_batch(Some(3)), 1);
+        assert_eq!(near_sdk::test_utils::get_logs().iter().filter(|log| log.contains("ft_mint")).count(), 3);
+        assert_eq!(contract.users.len(), 3);
+        assert_eq!(contract.get_total_user_num(), 4);
+        assert_eq!(contract.migrate_users_batch(None), 0);
+        assert_eq!(contract.get_total_user_num(), 4);
+        assert_eq!(contract.total_pending_withdrawal_amount, 30);
+        for i in 1..5 {
+            assert!(contract.users.contains_key(&accounts(i)));
+        }
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(1)).current_deposit.amount, 100);
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(3)).current_deposit.amount, 3);
+        // staked before checkpoints were recorded
+        assert_eq!(contract.get_staked_at(accounts(3), 0), U128(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MIGRATION_PENDING")]
+    fn test_solvency_during_migration() {
+        setup();
+        env::state_write(&state_v1());
+        let mut contract = Contract::migrate();
+        let _ = contract.check_solvency();
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown state layout")]
+    fn test_migrate_unknown_state() {
+        setup();
+        env::storage_write(STATE_KEY, &[1, 2, 3]);
+        Contract::migrate();
+    }
+}
@@ -2,14 +2,45 @@ use crate::*;
 
 impl Contract {
     pub fn assert_owner(&self) {
-        require!(
-            env::predecessor_account_id() == self.owner_id,
-            "ERR_NOT_ALLOWED"
-        );
+        require_or(env::predecessor_account_id() == self.owner_id, ContractError::NotAllowed);
+    }
+
+    pub fn assert_not_emergency(&self) {
+        require_or(!self.emergency_mode, ContractError::EmergencyMode);
+    }
+
+    pub fn assert_not_denylisted(&self, account_id: &AccountId) {
+        require_or(!self.denylist.contains(account_id), ContractError::Denylisted);
+    }
+
+    /// Denylisted accounts can never stake, and with the allowlist enabled only allowlisted accounts can.
+    pub fn internal_can_stake(&self, account_id: &AccountId) -> bool {
+        !self.denylist.contains(account_id) && (!self.allowlist_enabled || self.allowlist.contains(account_id))
     }
 
 }
 
+impl Contract {
+    /// Keeps `code` until it can be deployed, replacing anything staged before.
+    pub fn internal_stage_code(&mut self, code: &[u8]) {
+        require_or(!code.is_empty(), ContractError::EmptyCode);
+        let code_hash = env::sha256_array(code);
+        // the previous code can be restored without waiting, see deploy_staged_code
+        let deployable_time = if self.previous_code_hash == Some(code_hash) {
+            require_or(self.previous_state_version == self.state_version, ContractError::RollbackAcrossMigration);
+            nano_to_sec(env::block_timestamp())
+        } else {
+            nano_to_sec(env::block_timestamp()) + UPGRADE_DELAY_IN_SECS
+        };
+        env::storage_write(STAGED_CODE_KEY, code);
+        self.staged_code = Some(StagedCode { code_hash, deployable_time });
+        Event::CodeStaged {
+            code_hash: &code_hash.into(),
+            deployable_time
+        }.emit();
+    }
+}
+
 #[near_bindgen]
 impl Contract {
     #[payable]
@@ -19,6 +50,55 @@ impl Contract {
         self.owner_id = owner_id;
     }
 
+    /// Deploys the staged code and migrates the state in one batch, so a failing `migrate` reverts the deploy too.
+    /// The previous code hash is kept, staging that code again allows an immediate rollback, unless the deploy
+    /// migrated the state to a version the previous code can't read.
+    #[payable]
+    pub fn deploy_staged_code(&mut self) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        let staged_code = self.staged_code.as_ref().unwrap_or_else(|| ContractError::NoStagedCode.panic());
+        require_or(nano_to_sec(env::block_timestamp()) >= staged_code.deployable_time, ContractError::CodeNotDeployable);
+        let code_hash = staged_code.code_hash;
+        let state_version = self.state_version;
+        let code = env::storage_read(STAGED_CODE_KEY).unwrap_or_else(|| ContractError::NoStagedCode.panic());
+
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call_weight(
+                "migrate".to_string(),
+                Vec::new(),
+                NearToken::from_yoctonear(0),
+                Gas::from_gas(GAS_FOR_MIGRATE_CALL.as_gas()),
+                GasWeight::default(),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                .with_static_gas(Gas::from_tgas(10))
+                .on_code_deployed(code_hash.into(), state_version)
+            )
+    }
+
+    /// Runs on whichever code is live after the batch: the new code on success, the old one otherwise.
+    #[private]
+    pub fn on_code_deployed(&mut self, code_hash: Base58CryptoHash, previous_state_version: u32) {
+        if !is_promise_success() {
+            log!("Deploy failed, the previous code is still running.");
+            return;
+        }
+        self.previous_code_hash = self.current_code_hash;
+        self.previous_state_version = previous_state_version;
+        self.current_code_hash = Some(code_hash.into());
+        if self.staged_code.as_ref().map(|c| c.code_hash) == Some(code_hash.into()) {
+            self.staged_code = None;
+            env::storage_remove(STAGED_CODE_KEY);
+        }
+        Event::CodeDeployed {
+            code_hash: &code_hash,
+            time: nano_to_sec(env::block_timestamp())
+        }.emit();
+    }
+
     pub fn set_current_switch(&mut self, switch: bool) {
         self.assert_owner();
         self.current_switch = switch;
@@ -26,58 +106,266 @@ impl Contract {
 
     pub fn set_current_apr(&mut self, apr: u32) {
         self.assert_owner();
-        require!(apr > 0, "apr must be positive");
+        require_or(apr > 0, ContractError::InvalidApr);
+        self.internal_roll_interest_bound();
+        let old_yield = self.internal_max_current_yield();
         self.current_term_apr = apr;
+        self.internal_rescale_interest_bound(old_yield);
     }
 
     pub fn set_current_withdraw_delay(&mut self, delay_in_days: u32) {
         self.assert_owner();
-        require!(delay_in_days > 0, "delay_in_days must be positive");
+        require_or(delay_in_days > 0, ContractError::InvalidDelay);
         self.current_withdraw_delay = delay_in_days;
     }
 
+    pub fn set_instant_unstake_switch(&mut self, switch: bool) {
+        self.assert_owner();
+        self.instant_unstake_switch = switch;
+    }
+
+    pub fn set_instant_unstake_fee(&mut self, fee: u32) {
+        self.assert_owner();
+        require_or(fee < TERM_APR_DEMONINATOR, ContractError::InvalidFee);
+        self.instant_unstake_fee = fee;
+    }
+
+    /// Where instant unstake fees go, None keeps them in the reward reserve.
+    pub fn set_treasury(&mut self, treasury_id: Option<AccountId>) {
+        self.assert_owner();
+        self.treasury_id = treasury_id;
+    }
+
+    pub fn set_unbonding_interest_switch(&mut self, switch: bool) {
+        self.assert_owner();
+        let old_apr = self.internal_unbonding_apr();
+        self.unbonding_interest_switch = switch;
+        self.internal_record_unbonding_apr(old_apr);
+    }
+
+    pub fn set_unbonding_apr(&mut self, apr: u32) {
+        self.assert_owner();
+        require_or(apr <= TERM_APR_DEMONINATOR, ContractError::InvalidRate);
+        let old_apr = self.internal_unbonding_apr();
+        self.unbonding_term_apr = apr;
+        self.internal_record_unbonding_apr(old_apr);
+    }
+
+    pub fn set_referral_reward_rate(&mut self, rate: u32) {
+        self.assert_owner();
+        require_or(rate <= TERM_APR_DEMONINATOR, ContractError::InvalidRate);
+        self.referral_reward_rate = rate;
+    }
+
+    /// Interest earned so far keeps the mode it was earned in.
+    pub fn set_interest_mode(&mut self, mode: InterestMode) {
+        self.assert_owner();
+        if mode == self.interest_mode {
+            return;
+        }
+        self.internal_roll_interest_bound();
+        let timestamp = nano_to_sec(env::block_timestamp());
+        self.previous_interest_modes.push((self.interest_mode, timestamp));
+        self.interest_mode = mode;
+    }
+
+    /// Replaces the loyalty tier table, tiers must be given from the lowest to the highest.
+    pub fn set_loyalty_tiers(&mut self, tiers: Vec<LoyaltyTier>) {
+        self.assert_owner();
+        for pair in tiers.windows(2) {
+            require_or(
+                pair[1].min_amount >= pair[0].min_amount && pair[1].min_duration >= pair[0].min_duration,
+                ContractError::InvalidLoyaltyTiers
+            );
+        }
+        self.internal_roll_interest_bound();
+        let old_yield = self.internal_max_current_yield();
+        self.loyalty_tiers = tiers;
+        self.internal_rescale_interest_bound(old_yield);
+    }
+
+    pub fn set_keeper_reward(&mut self, reward: U128) {
+        self.assert_owner();
+        self.keeper_reward = reward.0;
+    }
+
+    /// Whether check_solvency turns off staking when the contract is in deficit.
+    pub fn set_solvency_auto_pause(&mut self, enabled: bool) {
+        self.assert_owner();
+        self.solvency_auto_pause = enabled;
+    }
+
+    pub fn set_current_total_cap(&mut self, cap: Option<U128>) {
+        self.assert_owner();
+        self.current_total_cap = cap.map(|c| c.0);
+    }
+
+    pub fn set_current_user_cap(&mut self, cap: Option<U128>) {
+        self.assert_owner();
+        self.current_user_cap = cap.map(|c| c.0);
+    }
+
+    pub fn set_min_stake_amount(&mut self, amount: U128) {
+        self.assert_owner();
+        self.min_stake_amount = amount.0;
+    }
+
+    pub fn set_min_fixed_deposit_amount(&mut self, amount: U128) {
+        self.assert_owner();
+        self.min_fixed_deposit_amount = amount.0;
+    }
+
+    /// Stops staking and interest accrual for good, users can only get their principal back through `emergency_withdraw`.
+    #[payable]
+    pub fn start_emergency_mode(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        require_or(!self.emergency_mode, ContractError::AlreadyEmergencyMode);
+        let timestamp = nano_to_sec(env::block_timestamp());
+        self.emergency_mode = true;
+        self.emergency_start_time = timestamp;
+        Event::EmergencyMode { time: timestamp }.emit();
+    }
+
+    pub fn set_allowlist_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        self.allowlist_enabled = enabled;
+    }
+
+    pub fn add_to_allowlist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids.iter() {
+            self.allowlist.insert(account_id);
+        }
+        Event::AllowlistAdd { account_ids: &account_ids }.emit();
+    }
+
+    pub fn remove_from_allowlist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids.iter() {
+            self.allowlist.remove(account_id);
+        }
+        Event::AllowlistRemove { account_ids: &account_ids }.emit();
+    }
+
+    pub fn add_to_denylist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids.iter() {
+            self.denylist.insert(account_id);
+        }
+        Event::DenylistAdd { account_ids: &account_ids }.emit();
+    }
+
+    pub fn remove_from_denylist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids.iter() {
+            self.denylist.remove(account_id);
+        }
+        Event::DenylistRemove { account_ids: &account_ids }.emit();
+    }
+
 }
 
 
 #[cfg(target_arch = "wasm32")]
 mod upgrade {
-    //use near_gas::*;
-    use near_sys as sys;
-
     use super::*;
 
-    /// Gas for calling migration call.
-    //pub const GAS_FOR_MIGRATE_CALL: Gas = Gas(5_000_000_000_000);
-    pub const GAS_FOR_MIGRATE_CALL: NearGas = NearGas::from_tgas(5);
-
-    /// Self upgrade and call migrate, optimizes gas by not loading into memory the code.
+    /// Stages new code for `deploy_staged_code`, it does not touch the running code.
     /// Takes as input non serialized set of bytes of the code.
     #[no_mangle]
-    pub fn upgrade() {
+    pub fn stage_code() {
         env::setup_panic_hook();
-        let contract: Contract = env::state_read().expect("ERR_CONTRACT_IS_NOT_INITIALIZED");
+        let mut contract: Contract = env::state_read().unwrap_or_else(|| ContractError::NotInitialized.panic());
         contract.assert_owner();
-        let current_id = env::current_account_id().as_bytes().to_vec();
-        let method_name = "migrate".as_bytes().to_vec();
-        unsafe {
-            // Load input (wasm code) into register 0.
-            sys::input(0);
-            // Create batch action promise for the current contract ID
-            let promise_id =
-                sys::promise_batch_create(current_id.len() as _, current_id.as_ptr() as _);
-            // 1st action in the Tx: "deploy contract" (code is taken from register 0)
-            sys::promise_batch_action_deploy_contract(promise_id, u64::MAX as _, 0);
-            // 2nd action in the Tx: call this_contract.migrate() with remaining gas
-            let attached_gas = env::prepaid_gas().as_gas() - env::used_gas().as_gas() - GAS_FOR_MIGRATE_CALL.as_gas();
-            sys::promise_batch_action_function_call(
-                promise_id,
-                method_name.len() as _,
-                method_name.as_ptr() as _,
-                0 as _,
-                0 as _,
-                0 as _,
-                attached_gas,
-            );
-        }
+        let code = env::input().unwrap_or_else(|| ContractError::NoInput.panic());
+        contract.internal_stage_code(&code);
+        env::state_write(&contract);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_yoctonear(ONE_YOCTO_NEAR));
+        testing_env!(context.build());
+        Contract::new(accounts(1), accounts(2))
+    }
+
+    #[test]
+    fn test_stage_code() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup(&mut context);
+        contract.internal_stage_code(b"new code");
+
+        let code_info = contract.get_code_info();
+        let expected = near_sdk::bs58::encode(env::sha256(b"new code")).into_string();
+        assert_eq!(String::from(&code_info.staged_code_hash.unwrap()), expected);
+        assert_eq!(code_info.staged_code_deployable_time, Some(UPGRADE_DELAY_IN_SECS));
+        assert_eq!(env::storage_read(STAGED_CODE_KEY).unwrap(), b"new code".to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "staged code is not deployable yet")]
+    fn test_deploy_before_delay() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup(&mut context);
+        contract.internal_stage_code(b"new code");
+        testing_env!(context.block_timestamp(to_nano(UPGRADE_DELAY_IN_SECS as u32 - 1)).build());
+        let _ = contract.deploy_staged_code();
+    }
+
+    #[test]
+    fn test_rollback_without_delay() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup(&mut context);
+        contract.current_code_hash = Some(env::sha256_array(b"new code"));
+        contract.previous_code_hash = Some(env::sha256_array(b"old code"));
+        contract.internal_stage_code(b"old code");
+        assert_eq!(contract.staged_code.as_ref().unwrap().deployable_time, 0);
+        let _ = contract.deploy_staged_code();
+
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert!(receipts[0].actions.iter().any(|action| matches!(
+            action,
+            near_sdk::mock::MockAction::DeployContract { code, .. } if code == b"old code"
+        )));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![near_sdk::PromiseResult::Successful(vec![])],
+        );
+        contract.on_code_deployed(env::sha256_array(b"old code").into(), STATE_VERSION);
+        assert_eq!(contract.current_code_hash, Some(env::sha256_array(b"old code")));
+        assert_eq!(contract.previous_code_hash, Some(env::sha256_array(b"new code")));
+        assert!(contract.staged_code.is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[should_panic(expected = "the previous code can't read the migrated state")]
+    fn test_no_rollback_across_migration() {
+        let mut context = VMContextBuilder::new();
+        let mut contract = setup(&mut context);
+        contract.current_code_hash = Some(env::sha256_array(b"old code"));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![near_sdk::PromiseResult::Successful(vec![])],
+        );
+        contract.on_code_deployed(env::sha256_array(b"new code").into(), STATE_VERSION - 1);
+        assert_eq!(contract.current_code_hash, Some(env::sha256_array(b"new code")));
+        contract.internal_stage_code(b"old code");
+    }
+}
@@ -0,0 +1,177 @@
+use crate::*;
+use near_contract_standards::fungible_token::events::FtTransfer;
+
+impl Contract {
+    /// Folds the interest earned so far into `accrued_interest` and restarts accrual at `timestamp`.
+    pub fn internal_settle_current_interest(&mut self, user_id: &AccountId, user: &mut User, timestamp: u64) {
+        let interest = self.internal_current_interest(&user.current_deposit, timestamp);
+        self.internal_settle_referral_reward(user_id, user, interest);
+        user.current_deposit.accrued_interest += interest;
+        user.current_deposit.last_stake_time = timestamp;
+        self.internal_count_settled_interest(interest);
+        self.total_accrued_interest += interest;
+    }
+
+    fn internal_assert_position_transfer(&self, sender_id: &AccountId, receiver_id: &AccountId) {
+        self.assert_not_emergency();
+        self.assert_not_denylisted(sender_id);
+        require_or(sender_id != receiver_id, ContractError::TransferToSelf);
+        require_or(receiver_id != &env::current_account_id(), ContractError::TransferToContract);
+        require_or(self.internal_can_stake(receiver_id), ContractError::ReceiverNotAllowed);
+    }
+
+    /// Checks shared by all position transfers, returns both users with their interest settled.
+    fn internal_prepare_position_transfer(&mut self, sender_id: &AccountId, receiver_id: &AccountId, timestamp: u64) -> (User, User) {
+        self.internal_assert_position_transfer(sender_id, receiver_id);
+        let mut sender = self.internal_unwrap_user_or_default(sender_id);
+        let mut receiver = self.internal_unwrap_user_or_default(receiver_id);
+        self.internal_settle_current_interest(sender_id, &mut sender, timestamp);
+        self.internal_settle_current_interest(receiver_id, &mut receiver, timestamp);
+        (sender, receiver)
+    }
+
+    /// Moves current deposit principal without any checks on the accounts, interest is settled for both first.
+    pub fn internal_move_current_position(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: Balance, timestamp: u64) {
+        let mut sender = self.internal_unwrap_user_or_default(sender_id);
+        let mut receiver = self.internal_unwrap_user_or_default(receiver_id);
+        self.internal_settle_current_interest(sender_id, &mut sender, timestamp);
+        self.internal_settle_current_interest(receiver_id, &mut receiver, timestamp);
+        require_or(sender.current_deposit.amount >= amount, ContractError::NotEnoughCurrentDeposit);
+
+        sender.current_deposit.amount -= amount;
+        if receiver.current_deposit.amount == 0 {
+            receiver.current_deposit.staking_since = timestamp;
+        }
+        receiver.current_deposit.amount += amount;
+        self.internal_set_user(sender_id, sender);
+        self.internal_set_user(receiver_id, receiver);
+    }
+
+    /// Used by transfer_position and by the receipt token, see receipt.rs.
+    pub fn internal_transfer_current_position(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: Balance, memo: Option<String>) {
+        self.internal_assert_position_transfer(sender_id, receiver_id);
+        require_or(amount > 0, ContractError::InvalidAmount);
+        if let Some(user_cap) = self.current_user_cap {
+            let receiver = self.internal_unwrap_user_or_default(receiver_id);
+            require_or(receiver.current_deposit.amount + amount <= user_cap, ContractError::ReceiverCapExceeded);
+        }
+        let timestamp = nano_to_sec(env::block_timestamp());
+        self.internal_move_current_position(sender_id, receiver_id, amount, timestamp);
+
+        Event::PositionTransfer {
+            sender_id,
+            receiver_id,
+            position_type: &"current_deposit".to_string(),
+            amount: &U128(amount),
+            time: timestamp
+        }.emit();
+        FtTransfer {
+            old_owner_id: sender_id,
+            new_owner_id: receiver_id,
+            amount: U128(amount),
+            memo: memo.as_deref(),
+        }.emit();
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Moves `amount` of the caller's current deposit principal to `receiver_id` without going through
+    /// the withdrawal queue. Interest earned so far stays with the sender.
+    #[payable]
+    pub fn transfer_position(&mut self, receiver_id: AccountId, amount: U128) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer_current_position(&sender_id, &receiver_id, amount.0, None);
+    }
+
+    /// Moves the caller's fixed deposit at `index` as a whole to `receiver_id`, keeping its terms.
+    #[payable]
+    pub fn transfer_fixed_position(&mut self, receiver_id: AccountId, index: u32) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        let timestamp = nano_to_sec(env::block_timestamp());
+        let (mut sender, mut receiver) = self.internal_prepare_position_transfer(&sender_id, &receiver_id, timestamp);
+        // the deposit moves with its current terms, like internal_move_fixed_deposit does for token transfers
+        self.internal_renew_fixed_deposits(&sender_id, &mut sender, timestamp);
+        require_or((index as usize) < sender.fixed_deposits.len(), ContractError::FixedDepositNotFound);
+        require_or(receiver.fixed_deposits.len() < MAX_FIXED_DEPOSITS_PER_USER, ContractError::FixedDepositLimit);
+
+        let deposit = sender.fixed_deposits.remove(index as usize);
+        let amount = U128(deposit.amount);
+        if let Some(token_id) = deposit.token_id.as_ref() {
+            self.nft.internal_transfer(&sender_id, &receiver_id, token_id, None, None);
+        }
+        receiver.fixed_deposits.push(deposit);
+        self.internal_set_user(&sender_id, sender);
+        self.internal_set_user(&receiver_id, receiver);
+
+        Event::PositionTransfer {
+            sender_id: &sender_id,
+            receiver_id: &receiver_id,
+            position_type: &"fixed_deposit".to_string(),
+            amount: &amount,
+            time: timestamp
+        }.emit();
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use super::*;
+    use crate::tests::set_context;
+
+    #[test]
+    fn test_transfer_position() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_current(accounts(2), 100 * YOCTO24, None);
+
+        set_context(accounts(2), ONE_YEAR_IN_SECS);
+        contract.transfer_position(accounts(3), U128(40 * YOCTO24));
+        let sender = contract.internal_unwrap_user_or_default(&accounts(2));
+        let receiver = contract.internal_unwrap_user_or_default(&accounts(3));
+        assert_eq!(sender.current_deposit.amount, 60 * YOCTO24);
+        assert_eq!(sender.current_deposit.accrued_interest, 32 * YOCTO24);
+        assert_eq!(receiver.current_deposit.amount, 40 * YOCTO24);
+        assert_eq!(receiver.current_deposit.accrued_interest, 0);
+        assert_eq!(contract.total_current_staked_amount, 100 * YOCTO24);
+
+        let mut user = receiver;
+        user.fixed_deposits.push(FixedDepositTerm { amount: 10, start_time: 0, duration: 1, accrued_interest: 0, apr: 0, token_id: None, auto_renew: false });
+        contract.internal_set_user(&accounts(3), user);
+        set_context(accounts(3), ONE_YEAR_IN_SECS);
+        contract.transfer_fixed_position(accounts(2), 0);
+        assert!(contract.internal_unwrap_user_or_default(&accounts(3)).fixed_deposits.is_empty());
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(2)).fixed_deposits[0].amount, 10);
+    }
+
+    #[test]
+    fn test_transfer_renewed_fixed_position() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_fixed(accounts(2), 100 * YOCTO24, 10, true);
+
+        // renewed twice, the receiver gets the deposit that runs until day 30
+        set_context(accounts(2), 25 * ONE_DAY_IN_SECS);
+        contract.transfer_fixed_position(accounts(3), 0);
+        let deposit = &contract.internal_unwrap_user_or_default(&accounts(3)).fixed_deposits[0];
+        assert_eq!(deposit.start_time, 20 * ONE_DAY_IN_SECS);
+        assert!(deposit.amount > 100 * YOCTO24);
+        assert_eq!(contract.total_fixed_staked_amount, deposit.amount);
+        assert_eq!(contract.total_fixed_interest, deposit.accrued_interest);
+        assert_eq!(contract.nft.owner_by_id.get(deposit.token_id.as_ref().unwrap()), Some(accounts(3)));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_CURRENT_DEPOSIT")]
+    fn test_transfer_too_much() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_current(accounts(2), 100, None);
+        set_context(accounts(2), 10);
+        contract.transfer_position(accounts(3), U128(101));
+    }
+}
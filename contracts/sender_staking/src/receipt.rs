@@ -0,0 +1,209 @@
+use crate::*;
+
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint, FtTransfer};
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+use near_contract_standards::fungible_token::receiver::ext_ft_receiver;
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
+use near_contract_standards::storage_management::{StorageBalance, StorageBalanceBounds, StorageManagement};
+
+/*
+ * The contract itself is a NEP-141 receipt token for current deposits, minted 1:1 on stake and
+ * burnt on unstake. A balance is the holder's current deposit principal, so a token transfer moves
+ * the position, the interest earned until then stays with the sender.
+ */
+
+const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas::from_tgas(10);
+const GAS_FOR_RECEIPT_TRANSFER_CALL: Gas = Gas::from_tgas(35);
+
+pub fn default_receipt_metadata() -> FungibleTokenMetadata {
+    FungibleTokenMetadata {
+        spec: FT_METADATA_SPEC.to_string(),
+        name: "Staked SENDER".to_string(),
+        symbol: "xSENDER".to_string(),
+        icon: None,
+        reference: None,
+        reference_hash: None,
+        decimals: 24,
+    }
+}
+
+impl Contract {
+    pub fn internal_receipt_mint(&self, owner_id: &AccountId, amount: Balance) {
+        if amount > 0 {
+            FtMint { owner_id, amount: U128(amount), memo: None }.emit();
+        }
+    }
+
+    /// Balances staked before the receipt token existed, minted as the legacy record moves into `users`.
+    pub fn internal_receipt_mint_legacy(&self, owner_id: &AccountId, amount: Balance) {
+        if amount > 0 {
+            FtMint { owner_id, amount: U128(amount), memo: Some("migration") }.emit();
+        }
+    }
+
+    pub fn internal_receipt_burn(&self, owner_id: &AccountId, amount: Balance) {
+        if amount > 0 {
+            FtBurn { owner_id, amount: U128(amount), memo: None }.emit();
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_receipt_metadata(&mut self, metadata: FungibleTokenMetadata) {
+        self.assert_owner();
+        metadata.assert_valid();
+        self.receipt_metadata = metadata;
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer_current_position(&sender_id, &receiver_id, amount.0, memo);
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        assert_one_yocto();
+        require_or(env::prepaid_gas() > GAS_FOR_RECEIPT_TRANSFER_CALL, ContractError::NotEnoughGas);
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer_current_position(&sender_id, &receiver_id, amount.0, memo);
+        ext_ft_receiver::ext(receiver_id.clone())
+            .with_static_gas(Gas::from_gas(0))
+            .with_unused_gas_weight(1)
+            .ft_on_transfer(sender_id.clone(), amount, msg)
+            .then(
+                Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                .ft_resolve_transfer(sender_id, receiver_id, amount)
+            )
+            .into()
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        U128(self.total_current_staked_amount)
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        U128(self.internal_unwrap_user_or_default(&account_id).current_deposit.amount)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    /// Moves back what the receiver did not use, as far as it still holds it.
+    #[private]
+    fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128 {
+        let unused = match env::promise_result_checked(0, 64) {
+            Ok(value) => serde_json::from_slice::<U128>(&value).map_or(amount.0, |unused| unused.0.min(amount.0)),
+            Err(_) => amount.0,
+        };
+        let refund = unused.min(self.internal_unwrap_user_or_default(&receiver_id).current_deposit.amount);
+        if refund > 0 {
+            let timestamp = nano_to_sec(env::block_timestamp());
+            self.internal_move_current_position(&receiver_id, &sender_id, refund, timestamp);
+            FtTransfer {
+                old_owner_id: &receiver_id,
+                new_owner_id: &sender_id,
+                amount: U128(refund),
+                memo: Some("refund"),
+            }.emit();
+        }
+        U128(amount.0 - refund)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for Contract {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        self.receipt_metadata.clone()
+    }
+}
+
+/*
+ * Holders never need to register, the contract pays for the storage of user records.
+ * Deposits are refunded so integrations which register first keep working.
+ */
+#[near_bindgen]
+impl StorageManagement for Contract {
+    #[payable]
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>) -> StorageBalance {
+        let _ = (account_id, registration_only);
+        let deposit = env::attached_deposit();
+        if !deposit.is_zero() {
+            Promise::new(env::predecessor_account_id()).transfer(deposit).detach();
+        }
+        self.empty_storage_balance()
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        assert_one_yocto();
+        require_or(amount.is_none_or(|amount| amount.is_zero()), ContractError::NoStorageToWithdraw);
+        self.empty_storage_balance()
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let _ = force;
+        false
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds { min: NearToken::from_yoctonear(0), max: Some(NearToken::from_yoctonear(0)) }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        let _ = account_id;
+        Some(self.empty_storage_balance())
+    }
+}
+
+impl Contract {
+    fn empty_storage_balance(&self) -> StorageBalance {
+        StorageBalance { total: NearToken::from_yoctonear(0), available: NearToken::from_yoctonear(0) }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use super::*;
+    use crate::tests::set_context;
+
+    #[test]
+    fn test_receipt_token() {
+        set_context(accounts(1), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_current(accounts(2), 100, None);
+        contract.stake_current(accounts(3), 50, None);
+        assert_eq!(contract.ft_total_supply(), U128(150));
+        assert_eq!(contract.ft_metadata().symbol, "xSENDER");
+
+        set_context(accounts(2), 10);
+        contract.ft_transfer(accounts(3), U128(30), None);
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(70));
+        assert_eq!(contract.ft_balance_of(accounts(3)), U128(80));
+        assert_eq!(contract.ft_total_supply(), U128(150));
+
+        set_context(accounts(2), 20);
+        contract.unstake_current();
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(0));
+        assert_eq!(contract.ft_total_supply(), U128(80));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::*;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Debug, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferralStats {
+    pub referee_count: u32,
+    #[serde(with = "u128_dec_format")]
+    pub pending_rewards: Balance,
+    #[serde(with = "u128_dec_format")]
+    pub paid_rewards: Balance,
+}
+
+impl Contract {
+    pub fn internal_get_referral_stats(&self, referrer_id: &AccountId) -> ReferralStats {
+        self.referrals.get(referrer_id).unwrap_or_default()
+    }
+
+    /// A referrer is recorded only once per user and can not be changed afterwards.
+    pub fn internal_record_referrer(&mut self, user_id: &AccountId, user: &mut User, referrer_id: Option<AccountId>) {
+        let Some(referrer_id) = referrer_id else { return };
+        if user.referrer.is_some() || &referrer_id == user_id {
+            return;
+        }
+        let mut stats = self.internal_get_referral_stats(&referrer_id);
+        stats.referee_count += 1;
+        self.referrals.insert(&referrer_id, &stats);
+        Event::ReferrerSet {
+            user_id,
+            referrer_id: &referrer_id,
+        }.emit();
+        user.referrer = Some(referrer_id);
+    }
+
+    /// Credits the referrer of `user` with its share of `interest`, call it whenever current deposit interest is settled.
+    pub fn internal_settle_referral_reward(&mut self, user_id: &AccountId, user: &User, interest: Balance) {
+        let Some(referrer_id) = user.referrer.as_ref() else { return };
+        let reward = interest * self.referral_reward_rate as u128 / TERM_APR_DEMONINATOR as u128;
+        if reward == 0 {
+            return;
+        }
+        let mut stats = self.internal_get_referral_stats(referrer_id);
+        stats.pending_rewards += reward;
+        self.referrals.insert(referrer_id, &stats);
+        self.total_pending_referral_rewards += reward;
+        Event::ReferralReward {
+            referrer_id,
+            user_id,
+            amount: &U128(reward),
+        }.emit();
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn claim_referral_rewards(&mut self) -> Promise {
+        let predecessor_id = env::predecessor_account_id();
+        self.assert_not_denylisted(&predecessor_id);
+        let mut stats = self.internal_get_referral_stats(&predecessor_id);
+        let amount = stats.pending_rewards;
+        require_or(amount > 0, ContractError::NoReferralRewards);
+
+        stats.pending_rewards = 0;
+        stats.paid_rewards += amount;
+        self.referrals.insert(&predecessor_id, &stats);
+        self.total_pending_referral_rewards -= amount;
+        self.total_paid_referral_rewards += amount;
+        self.internal_payout_sent(amount);
+
+        self.internal_ft_transfer(&predecessor_id, amount).then(
+            Self::ext(env::current_account_id())
+            .with_static_gas(Gas::from_tgas(20))
+            .on_claim_referral_rewards_complete(predecessor_id.clone(), U128(amount))
+        )
+    }
+
+    #[private]
+    pub fn on_claim_referral_rewards_complete(&mut self, referrer_id: AccountId, amount: U128) {
+        if !is_promise_success() {
+            self.internal_payout_resolved(amount.0, 0);
+            let mut stats = self.internal_get_referral_stats(&referrer_id);
+            stats.pending_rewards += amount.0;
+            stats.paid_rewards -= amount.0;
+            self.referrals.insert(&referrer_id, &stats);
+            self.total_pending_referral_rewards += amount.0;
+            self.total_paid_referral_rewards -= amount.0;
+            log!("Transfer failed.")
+        } else {
+            self.internal_payout_resolved(amount.0, amount.0);
+            Event::ReferralRewardClaim {
+                referrer_id: &referrer_id,
+                amount: &amount,
+            }.emit();
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use super::*;
+    use crate::tests::set_context;
+
+    #[test]
+    fn test_referral_reward() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.referral_reward_rate = 1000;
+
+        contract.stake_current(accounts(2), 100 * YOCTO24, Some(accounts(3)));
+        // the referrer can not be replaced, and self referral is ignored
+        contract.stake_current(accounts(2), 100 * YOCTO24, Some(accounts(4)));
+        contract.stake_current(accounts(4), 100 * YOCTO24, Some(accounts(4)));
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(2)).referrer, Some(accounts(3)));
+        assert_eq!(contract.internal_unwrap_user_or_default(&accounts(4)).referrer, None);
+        assert_eq!(contract.internal_get_referral_stats(&accounts(3)).referee_count, 1);
+
+        let user = contract.internal_unwrap_user_or_default(&accounts(2));
+        contract.internal_settle_referral_reward(&accounts(2), &user, 50 * YOCTO24);
+        assert_eq!(contract.internal_get_referral_stats(&accounts(3)).pending_rewards, 5 * YOCTO24);
+        assert_eq!(contract.total_pending_referral_rewards, 5 * YOCTO24);
+    }
+}
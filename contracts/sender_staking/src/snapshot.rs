@@ -0,0 +1,132 @@
+use crate::*;
+
+/*
+ * Staked balance (current deposit plus fixed deposits) from `timestamp` on, until the next checkpoint.
+ * Checkpoints are written whenever a balance changes, so past balances can be read on chain. There is
+ * at most one per day, later changes on the same day update its amount.
+ */
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct Checkpoint {
+    pub timestamp: u64,
+    pub amount: Balance,
+}
+
+pub fn staked_amount(user: &User) -> Balance {
+    user.current_deposit.amount + user.fixed_deposits.iter().map(|d| d.amount).sum::<Balance>()
+}
+
+/// Amount of the last checkpoint at or before `timestamp`.
+fn checkpoint_at(checkpoints: &Vector<Checkpoint>, timestamp: u64) -> Option<Balance> {
+    // index of the first checkpoint after timestamp
+    let (mut low, mut high) = (0, checkpoints.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        if checkpoints.get(mid).unwrap().timestamp <= timestamp {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    if low == 0 { None } else { checkpoints.get(low - 1).map(|c| c.amount) }
+}
+
+/// A checkpoint on the same day as the last one replaces its amount, which keeps the per user vectors small.
+fn push_checkpoint(checkpoints: &mut Vector<Checkpoint>, timestamp: u64, amount: Balance) {
+    match checkpoints.len().checked_sub(1) {
+        Some(last) if checkpoints.get(last).unwrap().timestamp / ONE_DAY_IN_SECS == timestamp / ONE_DAY_IN_SECS => {
+            let timestamp = checkpoints.get(last).unwrap().timestamp;
+            checkpoints.replace(last, &Checkpoint { timestamp, amount });
+        },
+        _ => checkpoints.push(&Checkpoint { timestamp, amount }),
+    }
+}
+
+impl Contract {
+    pub fn internal_total_staked(&self) -> Balance {
+        match self.total_staked_checkpoints.len() {
+            0 => 0,
+            len => self.total_staked_checkpoints.get(len - 1).unwrap().amount,
+        }
+    }
+
+    /// Records the staked balance of `user_id` and the total if it changed, call it before the record is written.
+    pub fn internal_checkpoint_staked(&mut self, user_id: &AccountId, old_user: Option<&User>, user: &User, timestamp: u64) {
+        let old_amount = old_user.map_or(0, staked_amount);
+        let amount = staked_amount(user);
+        if amount == old_amount {
+            return;
+        }
+        let mut checkpoints = self.internal_user_checkpoints(user_id);
+        push_checkpoint(&mut checkpoints, timestamp, amount);
+        self.user_checkpoints.insert(user_id, &checkpoints);
+
+        let total = self.internal_total_staked() + amount - old_amount;
+        push_checkpoint(&mut self.total_staked_checkpoints, timestamp, total);
+    }
+
+    /// Legacy records staked before checkpoints were recorded, they start with their balance at time 0,
+    /// the same way the total does.
+    pub fn internal_checkpoint_legacy_staked(&mut self, user_id: &AccountId, user: &User) {
+        let amount = staked_amount(user);
+        if amount > 0 && self.user_checkpoints.get(user_id).is_none() {
+            let mut checkpoints = self.internal_user_checkpoints(user_id);
+            push_checkpoint(&mut checkpoints, 0, amount);
+            self.user_checkpoints.insert(user_id, &checkpoints);
+        }
+    }
+
+    fn internal_user_checkpoints(&self, user_id: &AccountId) -> Vector<Checkpoint> {
+        self.user_checkpoints.get(user_id).unwrap_or_else(|| {
+            Vector::new(StorageKey::UserCheckpoints { account_hash: env::sha256_array(user_id.as_bytes()) })
+        })
+    }
+
+    pub fn internal_staked_at(&self, user_id: &AccountId, timestamp: u64) -> Balance {
+        match self.user_checkpoints.get(user_id) {
+            Some(checkpoints) => checkpoint_at(&checkpoints, timestamp).unwrap_or(0),
+            // a legacy record waiting for migrate_users_batch
+            None => self.legacy_users.get(user_id).map_or(0, |user| staked_amount(&user.into())),
+        }
+    }
+
+    pub fn internal_total_staked_at(&self, timestamp: u64) -> Balance {
+        checkpoint_at(&self.total_staked_checkpoints, timestamp).unwrap_or(0)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use super::*;
+    use crate::tests::set_context;
+
+    #[test]
+    fn test_staked_at() {
+        let day = ONE_DAY_IN_SECS;
+        set_context(accounts(0), day + 100);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_current(accounts(2), 10, None);
+        set_context(accounts(0), 2 * day + 100);
+        contract.stake_current(accounts(3), 20, None);
+        contract.stake_current(accounts(2), 5, None);
+        // the same day, it updates the last checkpoint
+        set_context(accounts(0), 2 * day + 200);
+        contract.stake_current(accounts(2), 1, None);
+        set_context(accounts(0), 3 * day);
+        let mut user = contract.internal_unwrap_user_or_default(&accounts(2));
+        user.current_deposit.amount = 0;
+        contract.internal_set_user(&accounts(2), user);
+
+        assert_eq!(contract.get_staked_at(accounts(2), day + 99), U128(0));
+        assert_eq!(contract.get_staked_at(accounts(2), day + 100), U128(10));
+        assert_eq!(contract.get_staked_at(accounts(2), 2 * day + 99), U128(10));
+        assert_eq!(contract.get_staked_at(accounts(2), 2 * day + 100), U128(16));
+        assert_eq!(contract.get_staked_at(accounts(2), 3 * day), U128(0));
+        assert_eq!(contract.user_checkpoints.get(&accounts(2)).unwrap().len(), 3);
+        assert_eq!(contract.get_staked_at(accounts(4), 3 * day), U128(0));
+        assert_eq!(contract.get_total_staked_at(day + 150), U128(10));
+        assert_eq!(contract.get_total_staked_at(2 * day + 100), U128(36));
+        assert_eq!(contract.get_total_staked_at(10 * day), U128(20));
+    }
+}
@@ -0,0 +1,320 @@
+use crate::*;
+use near_contract_standards::fungible_token::core::ext_ft_core;
+use near_sdk::PromiseError;
+
+const GAS_FOR_BALANCE_OF: Gas = Gas::from_tgas(10);
+const GAS_FOR_RESCUE_CALLBACK: Gas = Gas::from_tgas(50);
+const GAS_FOR_RESCUE_TRANSFER_CALLBACK: Gas = Gas::from_tgas(10);
+const GAS_FOR_SOLVENCY_CALLBACK: Gas = Gas::from_tgas(10);
+/// How long a deficit has to be seen before check_solvency pauses staking, so a balance read
+/// while a payout resolves can not pause it.
+const SOLVENCY_PAUSE_DELAY_IN_SECS: u64 = 60 * 60;
+
+/// Result of the last check_solvency, `balance` is what the contract held on `token_account_id`.
+/// The deficit is measured against the settled liabilities, the interest bound is only reported.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Debug, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SolvencyCheck {
+    pub timestamp: u64,
+    #[serde(with = "u128_dec_format")]
+    pub balance: Balance,
+    #[serde(with = "u128_dec_format")]
+    pub liabilities: Balance,
+    // upper bound of the interest not settled yet, on top of `liabilities`
+    #[serde(with = "u128_dec_format")]
+    pub unsettled_interest_bound: Balance,
+    // first check of the deficit seen in a row up to this one, None when not in deficit
+    pub deficit_since: Option<u64>,
+}
+
+impl Contract {
+    /// What the contract is known to owe in `token_account_id`: principal, settled interest, the
+    /// reward reserve and the payouts whose transfer has not resolved yet. Interest accrued since the
+    /// last settlement is not in it, see internal_liabilities_upper_bound.
+    pub fn internal_settled_liabilities(&self) -> Balance {
+        self.total_current_staked_amount
+            + self.total_current_accrued_interest
+            + self.total_pending_withdrawal_amount
+            + self.total_pending_withdrawal_interest
+            + self.total_fixed_staked_amount
+            + self.total_fixed_interest
+            + self.total_pending_referral_rewards
+            + self.reward_reserve
+            + self.total_payouts_in_flight
+    }
+
+    /// The settled liabilities plus an upper bound of the interest not settled yet, see
+    /// internal_unsettled_interest. rescue_tokens keeps this much, so it never takes interest users
+    /// earned but it can leave some surplus behind.
+    pub fn internal_liabilities_upper_bound(&self) -> Balance {
+        self.internal_settled_liabilities() + self.internal_unsettled_interest()
+    }
+
+    /// Call when `amount` is taken off the other totals before its transfer, it stays a liability
+    /// until internal_payout_resolved.
+    pub fn internal_payout_sent(&mut self, amount: Balance) {
+        self.total_payouts_in_flight += amount;
+    }
+
+    /// Call from the callback of a payout, `paid` is what actually left the contract.
+    pub fn internal_payout_resolved(&mut self, amount: Balance, paid: Balance) {
+        self.total_payouts_in_flight -= amount;
+        self.total_paid_out += paid;
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sends tokens that are not owed to anyone to `receiver_id`, e.g. tokens sent with a plain
+    /// `ft_transfer` or other tokens sent by mistake. For `token_account_id` only the balance above
+    /// the upper bound of the liabilities can be taken.
+    #[payable]
+    pub fn rescue_tokens(&mut self, token_id: AccountId, receiver_id: AccountId, amount: U128) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        require_or(amount.0 > 0, ContractError::InvalidAmount);
+        // interest of legacy records is only known once they are migrated
+        require_or(token_id != self.token_account_id || self.legacy_users.is_empty(), ContractError::MigrationPending);
+        ext_ft_core::ext(token_id.clone())
+            .with_static_gas(GAS_FOR_BALANCE_OF)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESCUE_CALLBACK)
+                .on_rescue_balance(token_id, receiver_id, amount, U128(self.total_paid_out))
+            )
+    }
+
+    /// Compares the contract's real balance with its settled liabilities, anyone can call it. With
+    /// `solvency_auto_pause` on, a deficit seen by checks for an hour turns off new current and
+    /// fixed deposits.
+    pub fn check_solvency(&mut self) -> Promise {
+        // liabilities of legacy records are only known once they are migrated
+        require_or(self.legacy_users.is_empty(), ContractError::MigrationPending);
+        ext_ft_core::ext(self.token_account_id.clone())
+            .with_static_gas(GAS_FOR_BALANCE_OF)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_SOLVENCY_CALLBACK)
+                .on_solvency_balance()
+            )
+    }
+
+    #[private]
+    pub fn on_solvency_balance(&mut self, #[callback_result] balance: Result<U128, PromiseError>) -> SolvencyCheck {
+        let balance = balance.unwrap_or_else(|_| ContractError::TokenBalanceUnavailable.panic()).0;
+        let timestamp = nano_to_sec(env::block_timestamp());
+        // the bound can run far ahead of what users earn, pausing on it would pause a solvent contract
+        let liabilities = self.internal_settled_liabilities();
+        let unsettled_interest_bound = self.internal_unsettled_interest();
+        let deficit_since = (balance < liabilities).then(|| {
+            self.last_solvency_check.as_ref().and_then(|check| check.deficit_since).unwrap_or(timestamp)
+        });
+        let check = SolvencyCheck { timestamp, balance, liabilities, unsettled_interest_bound, deficit_since };
+        Event::SolvencyCheck {
+            balance: &U128(check.balance),
+            liabilities: &U128(check.liabilities),
+            unsettled_interest_bound: &U128(check.unsettled_interest_bound),
+            surplus: &U128(check.balance.saturating_sub(check.liabilities)),
+            deficit: &U128(check.liabilities.saturating_sub(check.balance)),
+            time: check.timestamp
+        }.emit();
+        let persistent = deficit_since.is_some_and(|since| timestamp >= since + SOLVENCY_PAUSE_DELAY_IN_SECS);
+        if persistent && self.solvency_auto_pause && (self.current_switch || self.fixed_switch) {
+            self.current_switch = false;
+            self.fixed_switch = false;
+            log!("staking is paused, the contract is in deficit");
+        }
+        self.last_solvency_check = Some(check.clone());
+        check
+    }
+
+    #[private]
+    pub fn on_rescue_balance(
+        &mut self,
+        token_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        paid_out: U128,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> Promise {
+        let balance = balance.unwrap_or_else(|_| ContractError::TokenBalanceUnavailable.panic()).0;
+        let is_staking_token = token_id == self.token_account_id;
+        let surplus = if is_staking_token {
+            // the balance was read in an earlier block, whatever got paid out since rescue_tokens
+            // may still be in it while it is off the liabilities already
+            balance.saturating_sub(self.internal_liabilities_upper_bound() + self.total_paid_out - paid_out.0)
+        } else {
+            balance
+        };
+        require_or(amount.0 <= surplus, ContractError::RescueExceedsSurplus { surplus });
+
+        Event::TokensRescued {
+            token_id: &token_id,
+            receiver_id: &receiver_id,
+            amount: &amount,
+            time: nano_to_sec(env::block_timestamp())
+        }.emit();
+        let transfer = ext_ft_core::ext(token_id)
+            .with_attached_deposit(NearToken::from_yoctonear(ONE_YOCTO_NEAR))
+            .with_static_gas(Gas::from_gas(GAS_FOR_TRANSFER.as_gas()))
+            .ft_transfer(receiver_id, amount, None);
+        if !is_staking_token {
+            return transfer;
+        }
+        // a second rescue must not see these tokens as surplus while the transfer is in flight
+        self.internal_payout_sent(amount.0);
+        transfer.then(
+            Self::ext(env::current_account_id())
+            .with_static_gas(GAS_FOR_RESCUE_TRANSFER_CALLBACK)
+            .on_rescue_transfer_complete(amount)
+        )
+    }
+
+    #[private]
+    pub fn on_rescue_transfer_complete(&mut self, amount: U128) {
+        if !is_promise_success() {
+            self.internal_payout_resolved(amount.0, 0);
+            log!("Transfer failed.")
+        } else {
+            self.internal_payout_resolved(amount.0, amount.0);
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, get_logs};
+    use near_sdk::testing_env;
+
+    use super::*;
+    use crate::tests::{get_context, set_context};
+
+    #[test]
+    fn test_rescue_surplus() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_current(accounts(2), 100, None);
+        contract.reward_reserve = 20;
+        assert_eq!(contract.internal_liabilities_upper_bound(), 120);
+
+        let _ = contract.on_rescue_balance(accounts(1), accounts(0), U128(30), U128(0), Ok(U128(150)));
+        assert!(get_logs().iter().any(|log| log.contains("tokens_rescued") && log.contains("\"amount\":\"30\"")));
+        // the rescued tokens are owed until the transfer resolves, nothing is left to rescue
+        assert_eq!(contract.total_payouts_in_flight, 30);
+        assert_eq!(contract.internal_liabilities_upper_bound(), 150);
+        testing_env!(
+            get_context(accounts(0)).build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![near_sdk::PromiseResult::Successful(vec![])],
+        );
+        contract.on_rescue_transfer_complete(U128(30));
+        assert_eq!((contract.total_payouts_in_flight, contract.total_paid_out), (0, 30));
+
+        // any amount of another token
+        let _ = contract.on_rescue_balance(accounts(3), accounts(0), U128(500), U128(30), Ok(U128(500)));
+        assert_eq!(contract.total_payouts_in_flight, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_RESCUE_EXCEEDS_SURPLUS: only 30 can be rescued")]
+    fn test_rescue_after_payout() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_current(accounts(2), 100, None);
+        contract.reward_reserve = 20;
+        // 10 of the reserve paid out while the balance of 150 was read, it may still be in there
+        contract.reward_reserve -= 10;
+        contract.internal_payout_sent(10);
+        contract.internal_payout_resolved(10, 10);
+        let _ = contract.on_rescue_balance(accounts(1), accounts(0), U128(31), U128(0), Ok(U128(150)));
+    }
+
+    #[test]
+    fn test_payout_in_flight() {
+        set_context(accounts(2), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.instant_unstake_switch = true;
+        contract.stake_current(accounts(2), 100, None);
+
+        let _ = contract.instant_unstake();
+        assert_eq!(contract.total_current_staked_amount, 0);
+        assert_eq!(contract.internal_liabilities_upper_bound(), 100);
+    }
+
+    #[test]
+    fn test_solvency_check() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.current_switch = true;
+        contract.stake_current(accounts(2), 100, None);
+
+        let check = contract.on_solvency_balance(Ok(U128(100)));
+        assert_eq!(check.liabilities, 100);
+        assert!(contract.current_switch);
+
+        contract.solvency_auto_pause = true;
+        contract.on_solvency_balance(Ok(U128(90)));
+        assert_eq!(contract.get_last_solvency_check().unwrap().deficit_since, Some(0));
+        // a single check is not enough, nor a deficit that went away
+        assert!(contract.current_switch);
+        set_context(accounts(0), 1800);
+        contract.on_solvency_balance(Ok(U128(100)));
+        set_context(accounts(0), 3600);
+        contract.on_solvency_balance(Ok(U128(90)));
+        assert!(contract.current_switch);
+
+        set_context(accounts(0), 7200);
+        contract.on_solvency_balance(Ok(U128(90)));
+        assert!(!contract.current_switch);
+        assert_eq!(contract.get_last_solvency_check().unwrap().deficit_since, Some(3600));
+    }
+
+    #[test]
+    fn test_solvency_ignores_interest_bound() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.current_switch = true;
+        contract.solvency_auto_pause = true;
+        contract.stake_current(accounts(2), 100 * YOCTO24, None);
+
+        // the interest is not settled, the balance only covers the principal
+        for hour in 0..3 {
+            set_context(accounts(0), ONE_YEAR_IN_SECS + hour * 3600);
+            let check = contract.on_solvency_balance(Ok(U128(100 * YOCTO24)));
+            assert_eq!(check.liabilities, 100 * YOCTO24);
+            assert!(check.unsettled_interest_bound >= 32 * YOCTO24);
+            assert_eq!(check.deficit_since, None);
+        }
+        assert!(contract.current_switch);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_RESCUE_EXCEEDS_SURPLUS: only 30 can be rescued")]
+    fn test_rescue_principal() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_current(accounts(2), 100, None);
+        contract.reward_reserve = 20;
+        let _ = contract.on_rescue_balance(accounts(1), accounts(0), U128(31), U128(0), Ok(U128(150)));
+    }
+
+    #[test]
+    fn test_liabilities_include_interest() {
+        set_context(accounts(2), 0);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.stake_current(accounts(2), 100 * YOCTO24, None);
+
+        // not settled yet, at 32% a year
+        set_context(accounts(2), ONE_YEAR_IN_SECS);
+        assert_eq!(contract.internal_settled_liabilities(), 100 * YOCTO24);
+        assert_eq!(contract.internal_liabilities_upper_bound(), 132 * YOCTO24);
+        contract.stake_current(accounts(2), 10 * YOCTO24, None);
+        assert_eq!(contract.total_current_accrued_interest, 32 * YOCTO24);
+        assert_eq!(contract.internal_settled_liabilities(), 142 * YOCTO24);
+        assert_eq!(contract.internal_liabilities_upper_bound(), 142 * YOCTO24);
+    }
+}
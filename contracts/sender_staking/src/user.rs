@@ -1,4 +1,5 @@
 use crate::*;
+use near_contract_standards::non_fungible_token::TokenId;
 
 
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Debug, Clone)]
@@ -11,9 +12,12 @@ pub struct CurrentDepositTerm {
     pub last_unstake_time: u64,
     #[serde(with = "u128_dec_format")]
     pub accrued_interest: Balance,
+    // start of the uninterrupted staking period, used for loyalty tiers. last_stake_time is
+    // refreshed on every settlement so it can not serve for that.
+    pub staking_since: u64,
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Serialize, Debug, Clone)]
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct FixedDepositTerm {
     /// A copy of an user ID.
@@ -22,7 +26,10 @@ pub struct FixedDepositTerm {
     pub start_time: u64,
     pub duration: u64, // Deposit term in seconds
     #[serde(with = "u128_dec_format")]
-    pub accrued_interest: Balance,
+    pub accrued_interest: Balance, // interest paid at maturity, fixed when the deposit is made
+    pub apr: u32,
+    pub token_id: Option<TokenId>, // see fixed_deposit.rs
+    pub auto_renew: bool, // rolled over into a new term at maturity
 }
 
 /*
@@ -37,6 +44,7 @@ pub struct User {
     pub withdrawable_amount: Balance, //
     pub current_deposit: CurrentDepositTerm,
     pub fixed_deposits: Vec<FixedDepositTerm>, // Support multiple fixed deposits.
+    pub referrer: Option<AccountId>,
 }
 
 impl User {
@@ -48,22 +56,208 @@ impl User {
                 last_stake_time: 0,
                 last_unstake_time: 0,
                 accrued_interest: 0,
+                staking_since: 0,
             },
             fixed_deposits: Vec::new(),
+            referrer: None,
+        }
+    }
+}
+
+/*
+ * Layouts of users stored before records were versioned
+ */
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct CurrentDepositTermV0 {
+    pub amount: Balance,
+    pub last_stake_time: u64,
+    pub last_unstake_time: u64,
+    pub accrued_interest: Balance,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct FixedDepositTermV0 {
+    pub amount: Balance,
+    pub start_time: u64,
+    pub duration: u64,
+    pub accrued_interest: Balance,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct UserV0 {
+    pub withdrawable_amount: Balance,
+    pub current_deposit: CurrentDepositTermV0,
+    pub fixed_deposits: Vec<FixedDepositTermV0>,
+}
+
+impl From<UserV0> for User {
+    fn from(old: UserV0) -> Self {
+        Self {
+            withdrawable_amount: old.withdrawable_amount,
+            current_deposit: CurrentDepositTerm {
+                amount: old.current_deposit.amount,
+                last_stake_time: old.current_deposit.last_stake_time,
+                last_unstake_time: old.current_deposit.last_unstake_time,
+                accrued_interest: old.current_deposit.accrued_interest,
+                // the best known lower bound of the staking period
+                staking_since: old.current_deposit.last_stake_time,
+            },
+            fixed_deposits: old.fixed_deposits.into_iter().map(|d| FixedDepositTerm {
+                amount: d.amount,
+                start_time: d.start_time,
+                duration: d.duration,
+                accrued_interest: d.accrued_interest,
+                // no release could open fixed deposits, these records never had a token
+                apr: 0,
+                token_id: None,
+                auto_renew: false,
+            }).collect(),
+            referrer: None,
+        }
+    }
+}
+
+/*
+ * Stored form of a user, upgraded to the latest `User` when read.
+ * Records written before versioning are a bare `UserV0` without any tag. They are told apart by
+ * their first 16 bytes: a legacy record starts with its withdrawable_amount, which can never be
+ * u128::MAX, so versioned records start with that marker followed by the version number.
+ * To change `User` once it is released: freeze it as `UserVn`, add a variant and a conversion.
+ */
+#[derive(Debug, Clone)]
+pub enum VersionedUser {
+    V0(UserV0),
+    V1(User),
+}
+
+const VERSIONED_USER_MARKER: u128 = u128::MAX;
+
+impl BorshSerialize for VersionedUser {
+    fn serialize<W: borsh::io::Write>(&self, writer: &mut W) -> borsh::io::Result<()> {
+        match self {
+            VersionedUser::V0(user) => BorshSerialize::serialize(user, writer),
+            VersionedUser::V1(user) => {
+                BorshSerialize::serialize(&VERSIONED_USER_MARKER, writer)?;
+                BorshSerialize::serialize(&1u8, writer)?;
+                BorshSerialize::serialize(user, writer)
+            },
+        }
+    }
+}
+
+impl BorshDeserialize for VersionedUser {
+    fn deserialize_reader<R: borsh::io::Read>(reader: &mut R) -> borsh::io::Result<Self> {
+        let head = u128::deserialize_reader(reader)?;
+        if head != VERSIONED_USER_MARKER {
+            // legacy record, head is its withdrawable_amount
+            return Ok(VersionedUser::V0(UserV0 {
+                withdrawable_amount: head,
+                current_deposit: CurrentDepositTermV0::deserialize_reader(reader)?,
+                fixed_deposits: Vec::<FixedDepositTermV0>::deserialize_reader(reader)?,
+            }));
         }
+        match u8::deserialize_reader(reader)? {
+            1 => Ok(VersionedUser::V1(User::deserialize_reader(reader)?)),
+            version => Err(borsh::io::Error::new(
+                borsh::io::ErrorKind::InvalidData,
+                format!("unknown user version {}", version),
+            )),
+        }
+    }
+}
+
+impl From<VersionedUser> for User {
+    fn from(user: VersionedUser) -> Self {
+        match user {
+            VersionedUser::V0(old) => old.into(),
+            VersionedUser::V1(user) => user,
+        }
+    }
+}
+
+impl From<User> for VersionedUser {
+    fn from(user: User) -> Self {
+        VersionedUser::V1(user)
     }
 }
 
 impl Contract {
    pub fn internal_get_user(&self, user_id: &AccountId) -> Option<User> {
-       self.users.get(user_id).map(|o| o.into())
+       match self.users.get(user_id) {
+           Some(user) => Some(user.clone().into()),
+           None => self.legacy_users.get(user_id).map(|o| o.into()),
+       }
    }
 
    pub fn internal_unwrap_user_or_default(&self, user_id: &AccountId) -> User {
-       self.users.get(&user_id).unwrap_or( User::new() )
+       self.internal_get_user(user_id).unwrap_or( User::new() )
    }
 
    pub fn internal_set_user(&mut self, user_id: &AccountId, user: User) {
-       self.users.insert(user_id, &user.into());
+       let old_user = self.internal_get_user(user_id);
+       let timestamp = nano_to_sec(env::block_timestamp());
+       self.internal_update_voting_power(user_id, old_user.as_ref(), &user, timestamp);
+       if !self.legacy_users.is_empty() {
+           if let Some(legacy_user) = self.legacy_users.remove(user_id) {
+               self.internal_count_legacy_user(user_id, legacy_user);
+           }
+       }
+       self.internal_checkpoint_staked(user_id, old_user.as_ref(), &user, timestamp);
+       self.users.insert(user_id.clone(), user.into());
+   }
+
+   /// Legacy records were not part of the totals added with the migration, their withdrawable amount is all principal.
+   pub fn internal_count_legacy_user(&mut self, user_id: &AccountId, legacy_user: VersionedUser) {
+       let user = User::from(legacy_user);
+       self.internal_checkpoint_legacy_staked(user_id, &user);
+       self.internal_receipt_mint_legacy(user_id, user.current_deposit.amount);
+       self.total_pending_withdrawal_amount += user.withdrawable_amount;
+       self.internal_roll_interest_bound();
+       self.total_current_accrued_interest += user.current_deposit.accrued_interest;
+       self.unsettled_interest_bound += self.internal_current_interest(&user.current_deposit, nano_to_sec(env::block_timestamp()));
+   }
+
+   pub fn internal_get_unstaked_interest(&self, user_id: &AccountId) -> Balance {
+       self.unstaked_interests.get(user_id).unwrap_or(0)
+   }
+
+   pub fn internal_set_unstaked_interest(&mut self, user_id: &AccountId, interest: Balance) {
+       if interest == 0 {
+           self.unstaked_interests.remove(user_id);
+       } else {
+           self.unstaked_interests.insert(user_id, &interest);
+       }
    }
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_legacy_user() {
+        let legacy = UserV0 {
+            withdrawable_amount: 7,
+            current_deposit: CurrentDepositTermV0 {
+                amount: 100,
+                last_stake_time: 10,
+                last_unstake_time: 20,
+                accrued_interest: 3,
+            },
+            fixed_deposits: vec![FixedDepositTermV0 { amount: 50, start_time: 1, duration: 2, accrued_interest: 4 }],
+        };
+        let data = borsh::to_vec(&legacy).unwrap();
+
+        let user: User = VersionedUser::try_from_slice(&data).unwrap().into();
+        assert_eq!(user.withdrawable_amount, 7);
+        assert_eq!(user.current_deposit.amount, 100);
+        assert_eq!(user.current_deposit.last_unstake_time, 20);
+        assert_eq!(user.fixed_deposits[0].amount, 50);
+
+        // written back in the latest layout
+        let data = borsh::to_vec(&VersionedUser::from(user)).unwrap();
+        let user: User = VersionedUser::try_from_slice(&data).unwrap().into();
+        assert_eq!(user.current_deposit.accrued_interest, 3);
+        assert_eq!(user.fixed_deposits[0].accrued_interest, 4);
+    }
+}
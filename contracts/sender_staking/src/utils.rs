@@ -1,4 +1,14 @@
-use near_sdk::Timestamp;
+use near_sdk::{env, PromiseError, Timestamp};
+use crate::YOCTO18;
+
+#[allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+mod uint_types {
+    uint::construct_uint! {
+        /// 256-bit unsigned integer, used to keep fixed-point products from overflowing.
+        pub struct U256(4);
+    }
+}
+pub use uint_types::U256;
 
 pub mod u128_dec_format {
     use near_sdk::serde::de;
@@ -50,6 +60,28 @@ pub fn nano_to_sec(nano: u64) -> u64 {
     (nano / 10u64.pow(9)) as u64
 }
 
+/// Seconds since the unix epoch as an ISO 8601 UTC datetime, e.g. 2024-01-31T08:00:00Z.
+pub fn to_iso8601(sec: u64) -> String {
+    let days = (sec / 86400) as i64;
+    let rest = sec % 86400;
+    // civil date from days, Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rest / 3600, rest / 60 % 60, rest % 60)
+}
+
+/// Whether the promise the current callback is attached to succeeded, its return data is not needed.
+pub fn is_promise_success() -> bool {
+    !matches!(env::promise_result_checked(0, usize::MAX), Err(PromiseError::Failed))
+}
+
 pub fn as_u128(arr: &[u8]) -> u128 {
     ((arr[0] as u128) << 0) +
     ((arr[1] as u128) << 8) +
@@ -68,3 +100,43 @@ pub fn as_u128(arr: &[u8]) -> u128 {
     ((arr[14] as u128) << 112) +
     ((arr[15] as u128) << 120)
 }
+
+
+/* ========== FIXED POINT MATH, values scaled by YOCTO18 ========== */
+pub fn fixed_mul(a: u128, b: u128) -> u128 {
+    (U256::from(a) * U256::from(b) / U256::from(YOCTO18)).as_u128()
+}
+
+pub fn fixed_div(a: u128, b: u128) -> u128 {
+    (U256::from(a) * U256::from(YOCTO18) / U256::from(b)).as_u128()
+}
+
+/// base^exp by repeated squaring.
+pub fn fixed_pow(base: u128, exp: u64) -> u128 {
+    let mut result = YOCTO18;
+    let mut base = base;
+    let mut exp = exp;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = fixed_mul(result, base);
+        }
+        exp >>= 1;
+        if exp > 0 {
+            base = fixed_mul(base, base);
+        }
+    }
+    result
+}
+
+/// e^x using its Taylor series, stops once the terms vanish.
+pub fn fixed_exp(x: u128) -> u128 {
+    let mut result = YOCTO18;
+    let mut term = YOCTO18;
+    let mut n: u128 = 1;
+    while term > 0 {
+        term = fixed_mul(term, x) / n;
+        result += term;
+        n += 1;
+    }
+    result
+}
@@ -5,6 +5,7 @@ use crate::*;
 #[cfg_attr(feature = "test", derive(Clone))]
 pub struct Metadata {
     pub version: String,
+    pub state_version: u32,
     pub owner_id: AccountId,
     pub token_account_id: AccountId,
     pub current_switch: bool,
@@ -17,9 +18,64 @@ pub struct Metadata {
     pub acc_current_staked_amount: U128,
     pub total_current_staked_amount: U128,
 
+    // unstaked and not restaked since, withdrawn or not
     pub total_current_unstaked_amount: U128,
     pub total_current_unstaked_interest: U128,
 
+    pub total_pending_withdrawal_amount: U128,
+    pub total_pending_withdrawal_interest: U128,
+    pub total_withdrawn_amount: U128,
+    pub total_withdrawn_interest: U128,
+    pub total_accrued_interest: U128,
+    pub total_current_accrued_interest: U128,
+    // upper bound, see internal_interest_bound
+    pub unsettled_interest_bound: U128,
+
+    pub interest_mode: InterestMode,
+    // effective annual yield of current deposits, divided by 10000
+    pub current_term_apy: u32,
+
+    pub current_total_cap: Option<U128>,
+    pub current_user_cap: Option<U128>,
+    pub min_stake_amount: U128,
+    pub min_fixed_deposit_amount: U128,
+
+    pub allowlist_enabled: bool,
+
+    pub emergency_mode: bool,
+    pub emergency_start_time: u64,
+    pub total_emergency_withdrawn_amount: U128,
+
+    pub instant_unstake_switch: bool,
+    pub instant_unstake_fee: u32,
+    pub treasury_id: Option<AccountId>,
+    pub reward_reserve: U128,
+    pub total_instant_unstake_fee: U128,
+
+    pub unbonding_interest_switch: bool,
+    pub unbonding_term_apr: u32,
+
+    pub total_fixed_staked_amount: U128,
+    pub total_fixed_interest: U128,
+
+    pub keeper_reward: U128,
+    pub solvency_auto_pause: bool,
+    pub total_payouts_in_flight: U128,
+    pub total_paid_out: U128,
+
+    pub referral_reward_rate: u32,
+    pub total_pending_referral_rewards: U128,
+    pub total_paid_referral_rewards: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CodeInfo {
+    // base58 of the sha256, the same value codehash.sh prints
+    pub staged_code_hash: Option<Base58CryptoHash>,
+    pub staged_code_deployable_time: Option<u64>,
+    pub current_code_hash: Option<Base58CryptoHash>,
+    pub previous_code_hash: Option<Base58CryptoHash>,
 }
 
 #[near_bindgen]
@@ -28,6 +84,7 @@ impl Contract {
     pub fn get_metadata(&self) -> Metadata {
         Metadata {
             version: env!("CARGO_PKG_VERSION").to_string(),
+            state_version: self.state_version,
             owner_id: self.owner_id.clone(),
             token_account_id: self.token_account_id.clone(),
             current_switch: self.current_switch,
@@ -39,22 +96,126 @@ impl Contract {
             total_current_staked_amount: U128(self.total_current_staked_amount),
             total_current_unstaked_amount: U128(self.total_current_unstaked_amount),
             total_current_unstaked_interest: U128(self.total_current_unstaked_interest),
+            total_pending_withdrawal_amount: U128(self.total_pending_withdrawal_amount),
+            total_pending_withdrawal_interest: U128(self.total_pending_withdrawal_interest),
+            total_withdrawn_amount: U128(self.total_withdrawn_amount),
+            total_withdrawn_interest: U128(self.total_withdrawn_interest),
+            total_accrued_interest: U128(self.total_accrued_interest),
+            total_current_accrued_interest: U128(self.total_current_accrued_interest),
+            unsettled_interest_bound: U128(self.internal_interest_bound(nano_to_sec(env::block_timestamp()))),
+            interest_mode: self.interest_mode,
+            current_term_apy: self.internal_current_apy(),
+            current_total_cap: self.current_total_cap.map(U128),
+            current_user_cap: self.current_user_cap.map(U128),
+            min_stake_amount: U128(self.min_stake_amount),
+            min_fixed_deposit_amount: U128(self.min_fixed_deposit_amount),
+            allowlist_enabled: self.allowlist_enabled,
+            emergency_mode: self.emergency_mode,
+            emergency_start_time: self.emergency_start_time,
+            total_emergency_withdrawn_amount: U128(self.total_emergency_withdrawn_amount),
+            instant_unstake_switch: self.instant_unstake_switch,
+            instant_unstake_fee: self.instant_unstake_fee,
+            treasury_id: self.treasury_id.clone(),
+            reward_reserve: U128(self.reward_reserve),
+            total_instant_unstake_fee: U128(self.total_instant_unstake_fee),
+            unbonding_interest_switch: self.unbonding_interest_switch,
+            unbonding_term_apr: self.unbonding_term_apr,
+            total_fixed_staked_amount: U128(self.total_fixed_staked_amount),
+            total_fixed_interest: U128(self.total_fixed_interest),
+            keeper_reward: U128(self.keeper_reward),
+            solvency_auto_pause: self.solvency_auto_pause,
+            total_payouts_in_flight: U128(self.total_payouts_in_flight),
+            total_paid_out: U128(self.total_paid_out),
+            referral_reward_rate: self.referral_reward_rate,
+            total_pending_referral_rewards: U128(self.total_pending_referral_rewards),
+            total_paid_referral_rewards: U128(self.total_paid_referral_rewards),
+        }
+    }
+
+    pub fn get_code_info(&self) -> CodeInfo {
+        CodeInfo {
+            staged_code_hash: self.staged_code.as_ref().map(|c| c.code_hash.into()),
+            staged_code_deployable_time: self.staged_code.as_ref().map(|c| c.deployable_time),
+            current_code_hash: self.current_code_hash.map(|h| h.into()),
+            previous_code_hash: self.previous_code_hash.map(|h| h.into()),
         }
     }
 
     /* ========== VIEW FUNCTION ========== */
     pub fn get_total_user_num(&self) -> u32 {
-        let keys = self.users.keys_as_vector();
-        keys.len() as u32
+        self.users.len() + self.legacy_users.len() as u32
+    }
+
+    pub fn get_allowlist(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<AccountId> {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.allowlist.len());
+        self.allowlist.iter().skip(from_index as usize).take(limit as usize).collect()
+    }
+
+    pub fn get_denylist(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<AccountId> {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.denylist.len());
+        self.denylist.iter().skip(from_index as usize).take(limit as usize).collect()
+    }
+
+    pub fn is_allowlisted(&self, account_id: AccountId) -> bool {
+        self.allowlist.contains(&account_id)
+    }
+
+    pub fn is_denylisted(&self, account_id: AccountId) -> bool {
+        self.denylist.contains(&account_id)
+    }
+
+    pub fn get_referral_stats(&self, account_id: AccountId) -> ReferralStats {
+        self.internal_get_referral_stats(&account_id)
+    }
+
+    /// Vote-escrow power of `account_id`, it decays as its locks approach their end.
+    pub fn get_voting_power(&self, account_id: AccountId) -> U128 {
+        let user = self.internal_unwrap_user_or_default(&account_id);
+        U128(self.internal_user_voting_power(&user, nano_to_sec(env::block_timestamp())))
+    }
+
+    pub fn get_total_voting_power(&self) -> U128 {
+        U128(self.internal_total_voting_power(nano_to_sec(env::block_timestamp())))
+    }
+
+    /// Staked amount of `account_id`, current plus fixed deposits, at `timestamp` in seconds.
+    pub fn get_staked_at(&self, account_id: AccountId, timestamp: u64) -> U128 {
+        U128(self.internal_staked_at(&account_id, timestamp))
+    }
+
+    pub fn get_total_staked_at(&self, timestamp: u64) -> U128 {
+        U128(self.internal_total_staked_at(timestamp))
+    }
+
+    pub fn get_last_solvency_check(&self) -> Option<SolvencyCheck> {
+        self.last_solvency_check.clone()
+    }
+
+    pub fn get_loyalty_tiers(&self) -> Vec<LoyaltyTier> {
+        self.loyalty_tiers.clone()
+    }
+
+    /// The loyalty tier `user_id` has reached and what is missing for the next one.
+    pub fn get_user_tier(&self, user_id: AccountId) -> UserTierInfo {
+        let user = self.internal_unwrap_user_or_default(&user_id);
+        self.internal_user_tier_info(&user, nano_to_sec(env::block_timestamp()))
     }
 
     pub fn get_user(&self, user_id: AccountId) -> User {
         let mut user = self.internal_unwrap_user_or_default(&user_id);
         let timestamp = nano_to_sec(env::block_timestamp());
-        let delta_time = timestamp - user.current_deposit.last_stake_time;
-        let interest = (user.current_deposit.amount*delta_time as u128 *(self.current_term_apr as u128)/(TERM_APR_DEMONINATOR as u128))/(365*ONE_DAY_IN_SECS) as u128;
+        let interest = self.internal_current_interest(&user.current_deposit, timestamp);
+        let unbonding_interest = self.internal_unbonding_interest(&user_id, &user, timestamp);
 
         user.current_deposit.accrued_interest += interest;
+        user.withdrawable_amount += unbonding_interest;
+        if !self.emergency_mode {
+            for deposit in user.fixed_deposits.iter_mut() {
+                renew_fixed_deposit(deposit, self.fixed_term_apr, timestamp);
+            }
+        }
         user
     }
 
@@ -65,8 +226,7 @@ impl Contract {
     pub fn get_user_current_accrued_interest(&self, user_id: AccountId) -> U128 {
         let user = self.internal_unwrap_user_or_default(&user_id);
         let timestamp = nano_to_sec(env::block_timestamp());
-        let delta_time = timestamp - user.current_deposit.last_stake_time;
-        let interest = (user.current_deposit.amount*delta_time as u128 *(self.current_term_apr as u128)/(TERM_APR_DEMONINATOR as u128))/(365*ONE_DAY_IN_SECS) as u128;
+        let interest = self.internal_current_interest(&user.current_deposit, timestamp);
         U128(user.current_deposit.accrued_interest + interest)
     }
 
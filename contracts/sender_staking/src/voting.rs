@@ -0,0 +1,154 @@
+use crate::*;
+
+/// A fixed deposit locked for this long has a voting power equal to its amount.
+/// Fixed deposit durations must not exceed it.
+pub const MAX_LOCK_DURATION_IN_SECS: u64 = ONE_YEAR_IN_SECS;
+
+/*
+ * Fixed deposits ending at the same time, summed up so the total voting power can be derived
+ * without iterating users: sum(amount * (end - now)) = sum(amount * end) - now * sum(amount)
+ */
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Default)]
+pub struct VotingLock {
+    pub amount: Balance,
+    // amount * end / MAX_LOCK_DURATION_IN_SECS, rounded down so the total may be a few yocto short
+    pub bias: Balance,
+}
+
+fn lock_bias(amount: Balance, end_time: u64) -> Balance {
+    (U256::from(amount) * U256::from(end_time) / U256::from(MAX_LOCK_DURATION_IN_SECS)).as_u128()
+}
+
+fn decay(amount: Balance, time: u64) -> Balance {
+    (U256::from(amount) * U256::from(time) / U256::from(MAX_LOCK_DURATION_IN_SECS)).as_u128()
+}
+
+impl Contract {
+    /// Current deposits count as locked for the withdraw delay.
+    fn internal_current_voting_power(&self, amount: Balance) -> Balance {
+        decay(amount, ONE_DAY_IN_SECS * self.current_withdraw_delay as u64)
+    }
+
+    pub fn internal_user_voting_power(&self, user: &User, timestamp: u64) -> Balance {
+        let fixed_power: Balance = user.fixed_deposits.iter()
+            .map(|d| decay(d.amount, (d.start_time + d.duration).saturating_sub(timestamp)))
+            .sum();
+        fixed_power + self.internal_current_voting_power(user.current_deposit.amount)
+    }
+
+    pub fn internal_total_voting_power(&self, timestamp: u64) -> Balance {
+        let mut amount = self.total_voting_locked_amount;
+        let mut bias = self.total_voting_bias;
+        // locks which ended since the last checkpoint
+        for (end_time, lock) in self.voting_locks.iter() {
+            if end_time > timestamp {
+                break;
+            }
+            amount -= lock.amount;
+            bias -= lock.bias;
+        }
+        let fixed_power = bias.saturating_sub(decay(amount, timestamp));
+        fixed_power + self.internal_current_voting_power(self.total_current_staked_amount)
+    }
+
+    /// Drops the locks which have ended, their voting power has fully decayed.
+    pub fn internal_checkpoint_voting_locks(&mut self, timestamp: u64) {
+        while let Some(end_time) = self.voting_locks.min() {
+            if end_time > timestamp {
+                break;
+            }
+            let lock = self.voting_locks.remove(&end_time).unwrap();
+            self.total_voting_locked_amount -= lock.amount;
+            self.total_voting_bias -= lock.bias;
+        }
+    }
+
+    fn internal_add_voting_lock(&mut self, amount: Balance, end_time: u64) {
+        let bias = lock_bias(amount, end_time);
+        let mut lock = self.voting_locks.get(&end_time).unwrap_or_default();
+        lock.amount += amount;
+        lock.bias += bias;
+        self.voting_locks.insert(&end_time, &lock);
+        self.total_voting_locked_amount += amount;
+        self.total_voting_bias += bias;
+    }
+
+    fn internal_remove_voting_lock(&mut self, amount: Balance, end_time: u64) {
+        let bias = lock_bias(amount, end_time);
+        let mut lock = self.voting_locks.get(&end_time).unwrap_or_else(|| ContractError::VotingLockNotFound.panic());
+        lock.amount -= amount;
+        lock.bias -= bias;
+        if lock.amount == 0 {
+            self.voting_locks.remove(&end_time);
+        } else {
+            self.voting_locks.insert(&end_time, &lock);
+        }
+        self.total_voting_locked_amount -= amount;
+        self.total_voting_bias -= bias;
+    }
+
+    /// Moves the voting locks of `user_id` from its stored record to `user`, call it before the record is written.
+    pub fn internal_update_voting_power(&mut self, user_id: &AccountId, old_user: Option<&User>, user: &User, timestamp: u64) {
+        self.internal_checkpoint_voting_locks(timestamp);
+        let old_power = old_user.map_or(0, |old| self.internal_user_voting_power(old, timestamp));
+        if let Some(old) = old_user {
+            for d in old.fixed_deposits.iter().filter(|d| d.start_time + d.duration > timestamp) {
+                self.internal_remove_voting_lock(d.amount, d.start_time + d.duration);
+            }
+        }
+        for d in user.fixed_deposits.iter().filter(|d| d.start_time + d.duration > timestamp) {
+            self.internal_add_voting_lock(d.amount, d.start_time + d.duration);
+        }
+        let power = self.internal_user_voting_power(user, timestamp);
+        if power != old_power {
+            Event::VotingPowerChange {
+                user_id,
+                voting_power: &U128(power),
+                time: timestamp,
+            }.emit();
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::accounts;
+
+    use super::*;
+    use crate::tests::set_context;
+
+    fn lock(user: &mut User, amount: Balance, start_time: u64, duration: u64) {
+        user.fixed_deposits.push(FixedDepositTerm { amount, start_time, duration, accrued_interest: 0, apr: 0, token_id: None, auto_renew: false });
+    }
+
+    #[test]
+    fn test_voting_power() {
+        // the bias of a lock is rounded down, keep the test times round
+        let start = MAX_LOCK_DURATION_IN_SECS;
+        set_context(accounts(0), start);
+        let mut contract = Contract::new(accounts(0), accounts(1));
+        contract.current_withdraw_delay = 0;
+
+        let mut user = User::new();
+        lock(&mut user, 100 * YOCTO24, start, MAX_LOCK_DURATION_IN_SECS);
+        lock(&mut user, 100 * YOCTO24, start, MAX_LOCK_DURATION_IN_SECS / 2);
+        contract.internal_set_user(&accounts(2), user);
+        let mut user = User::new();
+        lock(&mut user, 200 * YOCTO24, start, MAX_LOCK_DURATION_IN_SECS / 2);
+        contract.internal_set_user(&accounts(3), user);
+        assert_eq!(contract.get_voting_power(accounts(2)), U128(150 * YOCTO24));
+        assert_eq!(contract.get_total_voting_power(), U128(250 * YOCTO24));
+
+        // half of the long lock is left, the short ones have ended
+        set_context(accounts(0), start + MAX_LOCK_DURATION_IN_SECS / 2);
+        assert_eq!(contract.get_voting_power(accounts(2)), U128(50 * YOCTO24));
+        assert_eq!(contract.get_voting_power(accounts(3)), U128(0));
+        assert_eq!(contract.get_total_voting_power(), U128(50 * YOCTO24));
+
+        let mut user = contract.internal_unwrap_user_or_default(&accounts(2));
+        user.fixed_deposits.remove(0);
+        contract.internal_set_user(&accounts(2), user);
+        assert_eq!(contract.get_total_voting_power(), U128(0));
+        assert!(contract.voting_locks.is_empty());
+    }
+}